@@ -0,0 +1,16 @@
+-- Mechanics as link entities, and the minimum age a Thing is meant for
+
+alter table bgg_thing add column min_age integer;
+
+create table bgg_mechanic (
+    id integer primary key generated always as identity,
+    created_at timestamp with time zone not null default now(),
+
+    bgg_id text not null unique,
+    name text not null
+);
+
+create table thing_mechanic (
+    thing_id integer not null references bgg_thing(id) on delete cascade,
+    mechanic_id integer not null references bgg_mechanic(id) on delete cascade
+);
@@ -0,0 +1,21 @@
+-- Join rows were never unique, so re-linking a Thing could duplicate them
+
+delete from thing_category a using thing_category b
+  where a.ctid < b.ctid and a.thing_id = b.thing_id and a.category_id = b.category_id;
+alter table thing_category add primary key (thing_id, category_id);
+
+delete from thing_family a using thing_family b
+  where a.ctid < b.ctid and a.thing_id = b.thing_id and a.family_id = b.family_id;
+alter table thing_family add primary key (thing_id, family_id);
+
+delete from thing_designer a using thing_designer b
+  where a.ctid < b.ctid and a.thing_id = b.thing_id and a.designer_id = b.designer_id;
+alter table thing_designer add primary key (thing_id, designer_id);
+
+delete from thing_publisher a using thing_publisher b
+  where a.ctid < b.ctid and a.thing_id = b.thing_id and a.publisher_id = b.publisher_id;
+alter table thing_publisher add primary key (thing_id, publisher_id);
+
+delete from thing_mechanic a using thing_mechanic b
+  where a.ctid < b.ctid and a.thing_id = b.thing_id and a.mechanic_id = b.mechanic_id;
+alter table thing_mechanic add primary key (thing_id, mechanic_id);
@@ -0,0 +1,5 @@
+-- Ids BGG had no Thing for, the last time we asked
+create table bgg_missing_thing (
+    bgg_id text primary key,
+    checked_at timestamp with time zone not null default now()
+);
@@ -0,0 +1,23 @@
+-- Links that only carry an id and a name, like rpggenre or videogameplatform,
+-- kept together with their BGG link type rather than a table each
+create table bgg_tag (
+    id integer primary key generated always as identity,
+    created_at timestamp with time zone not null default now(),
+
+    link_type text not null,
+    bgg_id text not null,
+    name text not null,
+    unique (link_type, bgg_id)
+);
+
+create table thing_tag (
+    thing_id integer not null references bgg_thing(id) on delete cascade,
+    tag_id integer not null references bgg_tag(id) on delete cascade,
+    primary key (thing_id, tag_id)
+);
+
+create type tag as (
+    link_type text,
+    bgg_id text,
+    name text
+);
@@ -0,0 +1,17 @@
+-- Calls to BGG made on each user's behalf, counted per day and endpoint
+create table upstream_usage (
+    authority text not null,
+    user_name text not null,
+    day date not null default current_date,
+    endpoint text not null,
+    calls integer not null default 0,
+    primary key (authority, user_name, day, endpoint)
+);
+
+-- Users whose daily quota of calls to BGG isn't the configured default
+create table upstream_quota (
+    authority text not null,
+    user_name text not null,
+    daily_calls integer not null,
+    primary key (authority, user_name)
+);
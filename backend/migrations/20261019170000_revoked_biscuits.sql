@@ -0,0 +1,6 @@
+-- Biscuits we refuse before they expire, by any of their revocation ids (base64, as the biscuits middleware reports them)
+create table revoked_biscuit (
+    revocation_id text primary key,
+    revoked_at timestamp with time zone not null default now(),
+    reason text
+);
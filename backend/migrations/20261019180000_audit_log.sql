@@ -0,0 +1,16 @@
+-- One row per request to the authenticated routes, kept for AUDIT_RETENTION_DAYS
+create table audit_log (
+    id bigint primary key generated always as identity,
+    at timestamp with time zone not null default now(),
+    authority text,
+    user_name text,
+    route text not null,
+    query text,
+    status integer not null,
+    cache_hits integer not null default 0,
+    cache_misses integer not null default 0,
+    upstream_calls integer not null default 0
+);
+
+create index audit_log_at on audit_log (at);
+create index audit_log_user on audit_log (authority, user_name, at);
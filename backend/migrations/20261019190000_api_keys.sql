@@ -0,0 +1,12 @@
+-- Locally issued API keys, for clients that can't get a biscuit from an authority.
+-- Only a hash of each key is kept; the key itself is shown once, when it's created.
+create table api_key (
+    id integer primary key generated always as identity,
+    created_at timestamp with time zone not null default now(),
+    revoked_at timestamp with time zone,
+
+    name text not null unique,
+    key_hash text not null unique,
+    tier text not null default 'default',
+    scopes text[] not null default '{}'
+);
@@ -0,0 +1,272 @@
+//! Locally issued API keys, for clients that can't get a biscuit from an upstream authority.
+//!
+//! Keys are managed with `bggapi-backend api-key`; we keep only their SHA-256 hashes.
+//! A request with a valid key in its X-API-Key header gets a biscuit minted for it on the spot,
+//! signed with a root key that lives only as long as the process, so that it goes through
+//! the same biscuit checks as everyone else's: `user(NAME)`, `api_key(NAME)`, `tier(TIER)`,
+//! and `right("bgg", SCOPE)` for each of its scopes (`admin(NAME)` for the "admin" scope).
+use std::{collections::HashMap, sync::{Arc, RwLock}, time::Duration};
+
+use axum::{
+    extract::{Request, State}, http::{HeaderMap, HeaderValue, StatusCode}, middleware::Next, response::Response
+};
+use biscuit_auth::{error, macros::{biscuit, fact}, KeyPair, PublicKey};
+use clap::{Parser, Subcommand};
+use sha2::{Digest, Sha256};
+use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+use crate::{auth::{Identity, Right, AUTH_HEADER}, db::{self, ApiKeyRecord}, Error};
+
+/// The header clients send their API keys in
+pub(crate) const API_KEY_HEADER: &str = "X-API-Key";
+/// The authority of the Identity an API key gives
+pub(crate) const LOCAL_AUTHORITY: &str = "api-key";
+/// The first command line argument for managing keys
+pub(crate) const SUBCOMMAND: &str = "api-key";
+
+const KEY_PREFIX: &str = "bgk_";
+const ADMIN_SCOPE: &str = "admin";
+const ALL_SCOPE: &str = "*";
+
+/// An unrevoked API key
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ApiKey {
+    pub name: String,
+    pub tier: String,
+    pub scopes: Vec<String>,
+}
+
+impl ApiKey {
+    pub fn identity(&self) -> Identity {
+        Identity{authority: LOCAL_AUTHORITY.to_string(), user: self.name.clone()}
+    }
+}
+
+/// The unrevoked keys, by hash, reloaded from the database periodically
+#[derive(Clone)]
+pub(crate) struct ApiKeys {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    db: Pool<Postgres>,
+    root: KeyPair,
+    keys: RwLock<HashMap<String, ApiKey>>,
+}
+
+impl std::fmt::Debug for ApiKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiKeys").field("root", &self.inner.root.public()).finish_non_exhaustive()
+    }
+}
+
+impl ApiKeys {
+    pub async fn load(db: Pool<Postgres>) -> Result<Self, Error> {
+        let keys = Self{inner: Arc::new(Inner{db, root: KeyPair::new(), keys: RwLock::new(HashMap::new())})};
+        keys.reload().await?;
+        Ok(keys)
+    }
+
+    async fn reload(&self) -> Result<(), Error> {
+        let keys = db::api_keys(&self.inner.db).await.map_err(mattak::Error::from)?
+            .into_iter()
+            .filter(|record| record.revoked_at.is_none())
+            .map(|ApiKeyRecord{name, key_hash, tier, scopes, ..}| (key_hash, ApiKey{name, tier, scopes}))
+            .collect::<HashMap<_, _>>();
+        debug!("{} API keys", keys.len());
+        *self.inner.keys.write().expect("API keys lock poisoned") = keys;
+        Ok(())
+    }
+
+    /// Reloads the keys every `every`, so that new and revoked keys take effect without a restart
+    pub fn spawn_reloader(&self, every: Duration) {
+        let keys = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(every).await;
+                if let Err(err) = keys.reload().await {
+                    warn!("couldn't reload API keys, keeping the ones we had: {err:?}");
+                }
+            }
+        });
+    }
+
+    /// The unrevoked key in the request's X-API-Key header, if there is one
+    pub fn lookup(&self, headers: &HeaderMap) -> Option<ApiKey> {
+        let key = headers.get(API_KEY_HEADER)?.to_str().ok()?;
+        self.inner.keys.read().expect("API keys lock poisoned").get(&hash(key)).cloned()
+    }
+
+    /// Just these keys, by the keys themselves, never reloaded
+    #[cfg(test)]
+    pub fn fixed(keys: Vec<(&str, ApiKey)>) -> Self {
+        let db = PgPoolOptions::new().connect_lazy("postgres://localhost/unused").expect("lazy pool");
+        let keys = keys.into_iter().map(|(key, api_key)| (hash(key), api_key)).collect();
+        Self{inner: Arc::new(Inner{db, root: KeyPair::new(), keys: RwLock::new(keys)})}
+    }
+
+    /// The root key the biscuits we mint for API keys are signed with
+    pub fn root(&self) -> PublicKey {
+        self.inner.root.public()
+    }
+
+    fn mint(&self, key: &ApiKey) -> Result<String, error::Token> {
+        let ApiKey{name, tier, scopes} = key;
+        let mut builder = biscuit!(r#"user({name}); api_key({name}); tier({tier});"#, name = name.as_str(), tier = tier.as_str());
+        for scope in scopes {
+            builder = if scope == ADMIN_SCOPE {
+                builder.fact(fact!("admin({name})", name = name.as_str()))?
+            } else {
+                builder.fact(fact!(r#"right("bgg", {scope})"#, scope = scope.as_str()))?
+            };
+        }
+        builder.build(&self.inner.root)?.to_base64()
+    }
+}
+
+fn hash(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn generate() -> String {
+    format!("{KEY_PREFIX}{}", hex::encode(rand::random::<[u8; 32]>()))
+}
+
+/// Middleware, to go ahead of the biscuits setup: swaps a valid API key for a biscuit.
+/// Requests with an unknown or revoked key are refused outright.
+pub(crate) async fn authenticate(State(keys): State<ApiKeys>, mut req: Request, next: Next) -> Result<Response, Error> {
+    if !req.headers().contains_key(API_KEY_HEADER) {
+        return Ok(next.run(req).await);
+    }
+    let key = keys.lookup(req.headers())
+        .ok_or((StatusCode::UNAUTHORIZED, "unknown or revoked API key"))?;
+    let token = keys.mint(&key)?;
+    req.headers_mut().insert(AUTH_HEADER, HeaderValue::from_str(&token).expect("base64 is a valid header value"));
+    Ok(next.run(req).await)
+}
+
+/// Manage the API keys that can be used instead of biscuits
+#[derive(Parser)]
+#[command(name = "bggapi-backend api-key")]
+pub(crate) struct Cli {
+    #[arg(long, env = "DATABASE_URL")]
+    db_connection_str: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Issue a new key and print it; it can't be shown again
+    Create {
+        /// What the key is for, e.g. the batch job that uses it
+        name: String,
+        /// The rate limit tier for requests made with the key
+        #[arg(long, default_value = "default")]
+        tier: String,
+        /// Comma-separated: search, thing, browse, * (all three) or admin
+        #[arg(long, value_delimiter = ',', required = true)]
+        scopes: Vec<String>,
+    },
+    /// List the keys (but not the keys themselves)
+    List,
+    /// Revoke the named key
+    Revoke {
+        name: String,
+    },
+}
+
+/// Runs `bggapi-backend api-key ...`, given the arguments after the program name
+pub(crate) async fn run_cli(args: impl IntoIterator<Item = String>) -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse_from(args);
+    let db = PgPoolOptions::new().max_connections(1).connect(&cli.db_connection_str).await?;
+
+    match cli.command {
+        Command::Create{name, tier, scopes} => {
+            if let Some(scope) = scopes.iter().find(|scope| !valid_scope(scope)) {
+                return Err(format!("unknown scope {scope:?}").into());
+            }
+            let key = generate();
+            db::create_api_key(&db, &name, &hash(&key), &tier, &scopes).await?;
+            println!("{key}");
+        }
+        Command::List => {
+            for ApiKeyRecord{name, tier, scopes, created_at, revoked_at, ..} in db::api_keys(&db).await? {
+                let revoked = revoked_at.map(|at| format!(" revoked {at}")).unwrap_or_default();
+                println!("{name}\ttier {tier}\tscopes {}\tcreated {created_at}{revoked}", scopes.join(","));
+            }
+        }
+        Command::Revoke{name} => {
+            if !db::revoke_api_key(&db, &name).await? {
+                return Err(format!("no unrevoked API key named {name:?}").into());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn valid_scope(scope: &str) -> bool {
+    scope == ALL_SCOPE || scope == ADMIN_SCOPE || Right::ALL.iter().any(|right| right.name() == scope)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, middleware, routing::get, Router};
+    use biscuit_auth::{macros::authorizer, Biscuit};
+    use mattak::biscuits::{keysets::AuthorityMap, middleware::{check, setup}};
+    use tower::{ServiceBuilder, ServiceExt};
+
+    use super::*;
+    use crate::keysets::KeySets;
+
+    #[test]
+    fn generated_keys_hash_distinctly() {
+        let (one, two) = (generate(), generate());
+        assert!(one.starts_with(KEY_PREFIX));
+        assert_ne!(one, two);
+        assert_eq!(hash(&one), hash(&one));
+        assert_ne!(hash(&one), hash(&two));
+    }
+
+    #[tokio::test]
+    async fn minted_biscuits_carry_scopes() {
+        let keys = ApiKeys::fixed(vec![]);
+        let key = ApiKey{name: "nightly".to_string(), tier: "batch".to_string(), scopes: vec!["thing".to_string(), "admin".to_string()]};
+
+        let token = Biscuit::from_base64(keys.mint(&key).expect("mint"), keys.root()).expect("parse minted biscuit");
+        let authorized = |right: Right| right.policy().build(&token).expect("build authorizer").authorize().is_ok();
+        assert!(authorized(Right::Thing));
+        assert!(!authorized(Right::Search));
+        assert!(authorizer!(r#"allow if user($user), admin($user), tier("batch");"#)
+            .build(&token).expect("build authorizer").authorize().is_ok());
+    }
+
+    #[tokio::test]
+    async fn keys_stand_in_for_biscuits() {
+        let nightly = ApiKey{name: "nightly".to_string(), tier: "default".to_string(), scopes: vec!["thing".to_string()]};
+        let keys = ApiKeys::fixed(vec![("bgk_nightly", nightly)]);
+        let key_sets = KeySets::fetch(AuthorityMap::from(Vec::<(&str, &str)>::new()), reqwest::Client::new(), keys.clone()).await
+            .expect("no key sets to fetch");
+        let app = Router::new().nest("/api", Router::new()
+            .route("/thing", get(|| async { "ok" }).layer(check(Right::Thing.policy())))
+            .route("/search", get(|| async { "ok" }).layer(check(Right::Search.policy())))
+            .layer(ServiceBuilder::new()
+                .layer(middleware::from_fn_with_state(keys, authenticate))
+                .layer(setup(key_sets, AUTH_HEADER))
+                .layer(check(authorizer!(r#"allow if user($user);"#)))
+            ));
+        let status = |path, key| {
+            let req = Request::get(path).header("host", "bgg.example.com").header(API_KEY_HEADER, key).body(Body::empty()).expect("request");
+            let app = app.clone();
+            async move { app.oneshot(req).await.expect("response").status() }
+        };
+
+        assert_eq!(status("/api/thing", "bgk_nightly").await, StatusCode::OK);
+        assert_eq!(status("/api/search", "bgk_nightly").await, StatusCode::FORBIDDEN);
+        // Revoked keys are left out when the keys are loaded, so they're as unknown as any other
+        assert_eq!(status("/api/thing", "bgk_unknown").await, StatusCode::UNAUTHORIZED);
+    }
+}
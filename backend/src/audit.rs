@@ -0,0 +1,133 @@
+//! An audit log of authenticated requests: who asked for what, and what it cost in calls to BGG.
+//!
+//! Work for a request can outlive its response (search events stream in, prefetches run in the background),
+//! so each request's entry is kept in a Tally that everything doing that work holds on to,
+//! and written out once the last of them lets go.
+use std::{
+    convert::Infallible, sync::{atomic::{AtomicI32, Ordering}, Arc, Mutex}, time::Duration
+};
+
+use axum::{
+    async_trait, extract::{FromRequestParts, MatchedPath, Request, State}, http::request::Parts, middleware::Next, response::Response
+};
+use sqlx::{Pool, Postgres};
+use tokio::{runtime::Handle, time::sleep};
+use tracing::{debug, warn};
+
+use crate::{auth, db::{self, AuditEntry}, keysets::KeySets};
+
+/// How often old entries are pruned
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// What the audit middleware needs
+#[derive(Clone, Debug)]
+pub(crate) struct Auditor {
+    db: Pool<Postgres>,
+    keys: KeySets,
+}
+
+impl Auditor {
+    pub fn new(db: Pool<Postgres>, keys: KeySets) -> Self {
+        Self{db, keys}
+    }
+
+    /// Deletes entries older than `retention_days`, now and periodically
+    pub fn spawn_pruner(&self, retention_days: i32) {
+        let db = self.db.clone();
+        tokio::spawn(async move {
+            loop {
+                match db::prune_audit(&db, retention_days).await {
+                    Ok(pruned) => debug!("pruned {pruned} audit entries"),
+                    Err(err) => warn!("couldn't prune audit log: {err:?}"),
+                }
+                sleep(PRUNE_INTERVAL).await;
+            }
+        });
+    }
+}
+
+/// The running count of what a request has cost, which handlers can take.
+/// Outside of the audit middleware it counts, but goes nowhere.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Tally(Arc<TallyInner>);
+
+#[derive(Debug, Default)]
+struct TallyInner {
+    cache_hits: AtomicI32,
+    cache_misses: AtomicI32,
+    upstream_calls: AtomicI32,
+    /// Filled in once the response is ready
+    entry: Mutex<Option<(Pool<Postgres>, AuditEntry)>>,
+}
+
+impl Tally {
+    pub fn upstream_call(&self) {
+        self.0.upstream_calls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cache(&self, hits: usize, misses: usize) {
+        self.0.cache_hits.fetch_add(hits.try_into().unwrap_or(i32::MAX), Ordering::Relaxed);
+        self.0.cache_misses.fetch_add(misses.try_into().unwrap_or(i32::MAX), Ordering::Relaxed);
+    }
+
+    fn finish(&self, db: Pool<Postgres>, entry: AuditEntry) {
+        *self.0.entry.lock().expect("tally lock poisoned") = Some((db, entry));
+    }
+}
+
+impl Drop for TallyInner {
+    fn drop(&mut self) {
+        let Some((db, mut entry)) = self.entry.get_mut().expect("tally lock poisoned").take() else {
+            return
+        };
+        entry.cache_hits = *self.cache_hits.get_mut();
+        entry.cache_misses = *self.cache_misses.get_mut();
+        entry.upstream_calls = *self.upstream_calls.get_mut();
+
+        match Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async move {
+                    if let Err(err) = db::record_audit(&db, &entry).await {
+                        warn!("couldn't record audit entry {entry:?}: {err:?}");
+                    }
+                });
+            }
+            Err(_) => warn!("no runtime to record audit entry {entry:?}"),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Tally {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts.extensions.get::<Tally>().cloned().unwrap_or_default())
+    }
+}
+
+/// Middleware, to go outside of authentication, so that refused requests are logged too
+pub(crate) async fn log_request(State(auditor): State<Auditor>, mut req: Request, next: Next) -> Response {
+    let who = auth::identify(&auditor.keys, req.headers(), req.uri());
+    let authority = who.as_ref().map(|who| who.authority.clone())
+        .or_else(|| auth::request_authority(req.headers(), req.uri()));
+    let route = req.extensions().get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| req.uri().path().to_string());
+    let query = req.uri().query().map(str::to_string);
+
+    let tally = Tally::default();
+    req.extensions_mut().insert(tally.clone());
+
+    let response = next.run(req).await;
+
+    tally.finish(auditor.db, AuditEntry{
+        authority,
+        user_name: who.map(|who| who.user),
+        route,
+        query,
+        status: response.status().as_u16().into(),
+        ..Default::default()
+    });
+    response
+}
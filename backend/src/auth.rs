@@ -0,0 +1,206 @@
+//! Working out who's asking, from the biscuit their request carries.
+//!
+//! The biscuits middleware does the actual authorization;
+//! this is for the parts of the gateway that need to know *which* user a request is for,
+//! like rate limiting, before or beside that happening.
+use axum::{async_trait, body::Body, extract::{FromRef, FromRequestParts}, http::{header, request::Parts, HeaderMap, Request, StatusCode, Uri}};
+use base64ct::{Base64, Encoding as _};
+use biscuit_auth::{macros::{authorizer, rule}, AuthorizerBuilder, Biscuit};
+use mattak::biscuits::middleware::setup::GetPublic;
+use tracing::trace;
+
+use crate::{api_keys::API_KEY_HEADER, keysets::KeySets};
+
+/// The header our clients send their biscuits in
+pub(crate) const AUTH_HEADER: &str = "Authorization";
+
+/// What a biscuit has to grant, beyond a user, to use a route: `right("bgg", "search")` and so on.
+/// Authorities can attenuate what they issue down to some routes; `right("bgg", "*")` grants them all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Right {
+    /// Searching BGG, and the Things found
+    Search,
+    /// Fetching a Thing from BGG by id
+    Thing,
+    /// Finding Things, categories, families and so on in our cache
+    Browse,
+}
+
+impl Right {
+    pub const ALL: [Right; 3] = [Right::Search, Right::Thing, Right::Browse];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Right::Search => "search",
+            Right::Thing => "thing",
+            Right::Browse => "browse",
+        }
+    }
+
+    /// The fact a biscuit needs, as advertised in the API doc
+    pub fn fact(self) -> String {
+        format!(r#"right("bgg", "{}")"#, self.name())
+    }
+
+    /// The authorizer policy for routes needing this right.
+    /// `operation("bgg", NAME)` is there for attenuating blocks to check against.
+    pub fn policy(self) -> AuthorizerBuilder {
+        authorizer!(r#"
+            operation("bgg", {name});
+            allow if user($user), right("bgg", {name});
+            allow if user($user), right("bgg", "*");
+        "#, name = self.name())
+    }
+}
+
+/// A user, as named by the upstream authority that issued their biscuit
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct Identity {
+    /// The host the request was made to, which determines the authority (see AUTH_MAP)
+    pub authority: String,
+    /// From the biscuit's `user($user)` fact
+    pub user: String,
+}
+
+/// Handlers behind the biscuits middleware can take the Identity of who they're serving.
+#[async_trait]
+impl<S> FromRequestParts<S> for Identity
+where KeySets: FromRef<S>, S: Send + Sync {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        identify(&KeySets::from_ref(state), &parts.headers, &parts.uri)
+            .ok_or((StatusCode::UNAUTHORIZED, "a biscuit with a user is required"))
+    }
+}
+
+/// Who a request is from, and the rate limit tier their biscuit or API key puts them in, if any
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Caller {
+    pub identity: Identity,
+    /// From the biscuit's `tier($tier)` fact, or the API key's tier
+    pub tier: Option<String>,
+    /// The biscuit's revocation ids, in base64 as the biscuits middleware has them; none for API keys,
+    /// which are revoked by leaving them out when they're reloaded
+    pub revocation_ids: Vec<String>,
+}
+
+/// The identity of a request's biscuit, if it has a valid one with a `user` fact,
+/// or of its API key, if it has a valid one of those instead.
+/// Nothing else about the biscuit is checked here.
+pub(crate) fn identify(keys: &KeySets, headers: &HeaderMap, uri: &Uri) -> Option<Identity> {
+    caller(keys, headers, uri).map(|caller| caller.identity)
+}
+
+/// Like `identify`, but with the tier and revocation ids too.
+/// Whether the biscuit has been revoked is for the caller to check.
+pub(crate) fn caller(keys: &KeySets, headers: &HeaderMap, uri: &Uri) -> Option<Caller> {
+    if headers.contains_key(API_KEY_HEADER) {
+        return keys.api_keys().lookup(headers).map(|key| Caller{identity: key.identity(), tier: Some(key.tier), revocation_ids: vec![]});
+    }
+
+    let token = headers.get(AUTH_HEADER)?;
+    let authority = request_authority(headers, uri)?;
+
+    // Looking up the authority's keys wants a whole request, but only looks at the headers and URI
+    let mut probe = Request::new(Body::empty());
+    *probe.uri_mut() = uri.clone();
+    *probe.headers_mut() = headers.clone();
+    let root = keys.get_public(&probe).ok()?;
+
+    let biscuit = Biscuit::from_base64(token.as_bytes(), root)
+        .inspect_err(|err| trace!("unreadable biscuit: {err:?}"))
+        .ok()?;
+    claims(&biscuit, authority)
+}
+
+/// The caller a biscuit names, if it has a `user` fact.
+/// Only the authority block's facts count, so attenuating a biscuit can't change its tier.
+fn claims(biscuit: &Biscuit, authority: String) -> Option<Caller> {
+    let mut authorizer = biscuit.authorizer().ok()?;
+    let users: Vec<(String,)> = authorizer.query(rule!("data($user) <- user($user)")).ok()?;
+    let (user,) = users.into_iter().next()?;
+    let tiers: Vec<(String,)> = authorizer.query(rule!("data($tier) <- tier($tier)")).unwrap_or_default();
+    let tier = tiers.into_iter().next().map(|(tier,)| tier);
+
+    let revocation_ids = biscuit.revocation_identifiers().iter().map(|id| Base64::encode_string(id)).collect();
+
+    Some(Caller{identity: Identity{authority, user}, tier, revocation_ids})
+}
+
+/// The host a request was made to, the same way the biscuits middleware works it out
+pub(crate) fn request_authority(headers: &HeaderMap, uri: &Uri) -> Option<String> {
+    forwarded_host(headers)
+        .or_else(|| header_str(headers, "X-Forwarded-Host"))
+        .or_else(|| header_str(headers, header::HOST.as_str()))
+        .or_else(|| uri.authority().map(|auth| auth.as_str().rsplit('@').next().unwrap_or_default().to_string()))
+}
+
+fn header_str(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers.get(name).and_then(|value| value.to_str().ok()).map(str::to_string)
+}
+
+fn forwarded_host(headers: &HeaderMap) -> Option<String> {
+    let forwarded = headers.get(header::FORWARDED)?.to_str().ok()?;
+    forwarded.split(',').next()?
+        .split(';')
+        .find_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            key.trim().eq_ignore_ascii_case("host").then(|| value.trim().trim_matches('"').to_string())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use biscuit_auth::{macros::{biscuit, block}, KeyPair};
+
+    use super::*;
+
+    fn authorized(token: &Biscuit, right: Right) -> bool {
+        right.policy().build(token).expect("build authorizer").authorize().is_ok()
+    }
+
+    #[test]
+    fn rights_per_route() {
+        let root = KeyPair::new();
+        let search = biscuit!(r#"user("alice"); right("bgg", "search");"#).build(&root).expect("build biscuit");
+        assert!(authorized(&search, Right::Search));
+        assert!(!authorized(&search, Right::Thing));
+        assert!(!authorized(&search, Right::Browse));
+
+        let everything = biscuit!(r#"user("alice"); right("bgg", "*");"#).build(&root).expect("build biscuit");
+        assert!(authorized(&everything, Right::Search));
+        assert!(authorized(&everything, Right::Thing));
+
+        let anonymous = biscuit!(r#"right("bgg", "*");"#).build(&root).expect("build biscuit");
+        assert!(!authorized(&anonymous, Right::Thing));
+
+        let rightless = biscuit!(r#"user("alice");"#).build(&root).expect("build biscuit");
+        assert!(!authorized(&rightless, Right::Search));
+    }
+
+    #[test]
+    fn attenuated_to_some_routes() {
+        let root = KeyPair::new();
+        let token = biscuit!(r#"user("alice"); right("bgg", "*");"#).build(&root).expect("build biscuit")
+            .append(block!(r#"check if operation("bgg", $op), ["thing", "browse"].contains($op);"#))
+            .expect("attenuate biscuit");
+        assert!(!authorized(&token, Right::Search));
+        assert!(authorized(&token, Right::Thing));
+        assert!(authorized(&token, Right::Browse));
+    }
+
+    #[test]
+    fn tier_from_authority_block() {
+        let root = KeyPair::new();
+        let token = biscuit!(r#"user("alice"); tier("batch");"#).build(&root).expect("build biscuit");
+        let caller = claims(&token, "example.com".to_string()).expect("caller");
+        assert_eq!(caller.identity, Identity{authority: "example.com".to_string(), user: "alice".to_string()});
+        assert_eq!(caller.tier.as_deref(), Some("batch"));
+
+        let untiered = biscuit!(r#"user("alice");"#).build(&root).expect("build biscuit")
+            .append(block!(r#"tier("unlimited");"#))
+            .expect("attenuate biscuit");
+        assert_eq!(claims(&untiered, "example.com".to_string()).expect("caller").tier, None);
+    }
+}
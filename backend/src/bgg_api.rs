@@ -1,18 +1,109 @@
-use std::time::Duration;
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
 
 use bounded_join_set::JoinSet;
 use mattak::querymapping::NoId;
-use quick_xml::{events::{BytesStart, Event}, name::QName, Reader};
-use reqwest::{Client, StatusCode};
+use quick_xml::{events::{BytesStart, Event}, name::QName};
+use reqwest::{Client, Response, StatusCode};
 use serde::Serialize;
 use sqlx::{Pool, Postgres};
-use tokio::time::sleep;
-use tracing::debug;
+use tokio::{task, time::sleep};
+use tracing::{debug, warn};
 
-use crate::{db::{BggThing, LinkData, ThingData}, Error};
+use crate::{db::{self, BggThing, LinkData, TagData, ThingData}, quota::Account, Error};
 
-// const XMLAPI: &str = "https://boardgamegeek.com/xmlapi";
-const XMLAPI2: &str = "https://boardgamegeek.com/xmlapi2";
+mod recording;
+mod xml;
+
+pub(crate) use recording::Traffic;
+pub(crate) use xml::ParseError;
+use xml::XmlReader;
+
+/// Our connection to the BGG XML API.
+/// The base URL is configurable so that we can point at a stand-in, like the fake-bgg binary.
+/// Traffic can also be recorded to disk, and replayed from there without any network access.
+/// A client acting on a user's behalf counts each request it makes to BGG against their quota, once however often it has to be asked again.
+#[derive(Clone)]
+pub(crate) struct BggClient {
+    http: Client,
+    xmlapi2: Arc<str>,
+    traffic: Arc<Traffic>,
+    account: Option<Arc<Account>>,
+}
+
+impl BggClient {
+    pub fn new(http: Client, xmlapi2: &str, traffic: Traffic) -> Self {
+        Self{http, xmlapi2: xmlapi2.trim_end_matches('/').into(), traffic: Arc::new(traffic), account: None}
+    }
+
+    /// The same client, counting its calls to BGG against this account
+    pub fn on_behalf_of(&self, account: Account) -> Self {
+        Self{account: Some(Arc::new(account)), ..self.clone()}
+    }
+
+    async fn get(&self, path_and_query: &str) -> Result<Response, Error> {
+        self.request(path_and_query, true).await
+    }
+
+    /// Asks again for what BGG wouldn't answer the first time,
+    /// which isn't counted again: the user only asked once.
+    async fn get_again(&self, path_and_query: &str) -> Result<Response, Error> {
+        self.request(path_and_query, false).await
+    }
+
+    async fn request(&self, path_and_query: &str, counted: bool) -> Result<Response, Error> {
+        let url = format!("{}{path_and_query}", self.xmlapi2);
+        match self.traffic.as_ref() {
+            Traffic::Live => {
+                if counted {
+                    self.count(path_and_query).await;
+                }
+                Ok(self.send(path_and_query, url).await?)
+            }
+            Traffic::Record(dir) => {
+                if counted {
+                    self.count(path_and_query).await;
+                }
+                let rz = self.send(path_and_query, url).await?;
+                let status = rz.status();
+                let body = rz.bytes().await?;
+                recording::record(dir, path_and_query, status, &body).await?;
+                recording::rebuild(status, body)
+            }
+            Traffic::Replay(dir) => recording::replay(dir, path_and_query).await,
+        }
+    }
+
+    /// Makes a request to BGG itself, counting and timing it by endpoint
+    async fn send(&self, path_and_query: &str, url: String) -> Result<Response, reqwest::Error> {
+        let endpoint = endpoint(path_and_query).to_string();
+        let started = Instant::now();
+        let result = self.http.get(url).send().await;
+        metrics::histogram!("bgg_request_duration_seconds", "endpoint" => endpoint.clone()).record(started.elapsed());
+        let status = match &result {
+            Ok(rz) => rz.status().as_u16().to_string(),
+            Err(_) => "error".to_string(),
+        };
+        metrics::counter!("bgg_requests_total", "endpoint" => endpoint, "status" => status).increment(1);
+        result
+    }
+
+    async fn count(&self, path_and_query: &str) {
+        if let Some(account) = &self.account {
+            account.record(path_and_query).await;
+        }
+    }
+
+    fn count_cache(&self, hits: usize, misses: usize) {
+        if let Some(account) = &self.account {
+            account.cache(hits, misses);
+        }
+    }
+}
+
+/// The endpoint a request to BGG is for, e.g. "thing" or "search"
+pub(crate) fn endpoint(path_and_query: &str) -> &str {
+    path_and_query.split('?').next().unwrap_or_default().trim_matches('/')
+}
 
 fn string_attr (tag: &BytesStart, name: &str) -> String {
     tag.try_get_attribute(name)
@@ -22,52 +113,248 @@ fn string_attr (tag: &BytesStart, name: &str) -> String {
         .to_string()
 }
 
+/// Reads a numeric `value` attribute leniently:
+/// BGG sometimes sends `value=""` (or stranger), and that shouldn't cost us the rest of the Thing.
+fn int_value(tag: &BytesStart, bgg_id: &str) -> Option<i32> {
+    let value = string_attr(tag, "value");
+    match value.trim().parse() {
+        Ok(n) => Some(n),
+        Err(err) => {
+            metrics::counter!("bgg_unparseable_values_total").increment(1);
+            warn!("Thing {bgg_id}: ignoring <{}> value {value:?}: {err}", String::from_utf8_lossy(tag.name().as_ref()));
+            None
+        }
+    }
+}
+
+/// Link types we keep as tags, rather than as entities with their own tables and resources:
+/// BGG has nothing more to tell us about them than an id and a name.
+const TAG_LINK_TYPES: &[&str] = &[
+    "boardgameaccessory",
+    "rpg", "rpgartist", "rpgcategory", "rpgdesigner", "rpggenre", "rpgmechanic",
+    "rpgproducer", "rpgpublisher", "rpgsetting", "rpgseries",
+    "videogamecompilation", "videogamedeveloper", "videogamefranchise", "videogamegenre",
+    "videogamemode", "videogameplatform", "videogamepublisher", "videogameseries", "videogametheme",
+];
+
 const BGG_THING_BATCH_SIZE: usize = 20;
 
 #[derive(Default, Serialize)]
 pub(crate) struct SearchItem {
     id: String,
-    kind: String
+    kind: String,
+    /// The primary name, if that's what BGG matched the query on
+    name: Option<String>,
+    /// The alternate name BGG matched the query on instead, if it did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alternate_name: Option<String>,
+    year_published: Option<i32>,
 }
 
-pub(crate) async fn search(client: Client, db: &Pool<Postgres>, query: String, bgg_limit: usize) -> Result<(Vec<SearchItem>, Vec<ThingData>), Error>
+impl SearchItem {
+    // On the primary name: 0 for an exact match, 1 for a prefix, 2 for a substring.
+    // 3 for a match only on an alternate name, 4 otherwise
+    fn relevance(&self, query: &str) -> u8 {
+        let matches = |name: &Option<String>| name.as_ref().is_some_and(|name| name.to_lowercase().contains(query));
+        match self.name.as_ref().map(|n| n.to_lowercase()) {
+            Some(name) if name == query => 0,
+            Some(name) if name.starts_with(query) => 1,
+            Some(name) if name.contains(query) => 2,
+            _ if matches(&self.alternate_name) => 3,
+            _ => 4
+        }
+    }
+}
+
+pub(crate) struct SearchPage {
+    pub total: usize,
+    pub items: Vec<SearchItem>,
+    pub things: Vec<ThingData>,
+    pub missing: Vec<MissingThing>,
+}
+
+/// A search result whose Thing details we couldn't get from BGG
+#[derive(Serialize, Debug)]
+pub(crate) struct MissingThing {
+    bgg_id: String,
+    reason: String,
+}
+
+/// Searches BGG, ranks the results by how well their primary names match the query,
+/// and fetches Thing details for just the requested page of results.
+/// Pages are counted from 1.
+pub(crate) async fn search(client: BggClient, db: &Pool<Postgres>, query: String, page: usize, per_page: usize, bgg_limit: usize) -> Result<SearchPage, Error>
 {
-    let url = format!("{XMLAPI2}/search?query={}", query);
-    let rz = client.get(url).send().await?;
+    let mut pending = start_search(client, db, query, page, per_page, bgg_limit).await?;
+    let mut things = std::mem::take(&mut pending.cached);
+
+    while let Some(fetched_things) = pending.next_batch().await {
+        things.extend(fetched_things);
+    }
+
+    things.sort_by_key(|thing| pending.items.iter().position(|item| item.id == thing.bgg_id));
+
+    Ok(SearchPage{total: pending.total, items: pending.items, things, missing: pending.missing})
+}
+
+type FetchJob = Result<Vec<BggThing<NoId>>, Error>;
+
+/// A search whose page of items is known, but whose Things may still be on their way from BGG.
+pub(crate) struct PendingSearch {
+    pub total: usize,
+    pub items: Vec<SearchItem>,
+    pub cached: Vec<ThingData>,
+    /// Filled in as fetch jobs fail or come back short
+    pub missing: Vec<MissingThing>,
+    fetchset: JoinSet<FetchJob>,
+    batches: HashMap<task::Id, Vec<String>>,
+}
 
-    let text = rz.text().await?;
+impl PendingSearch {
+    /// Waits for the next fetch job to finish and returns the Things it got,
+    /// recording any ids it couldn't get in `missing`.
+    /// Returns None once every job has been joined.
+    pub async fn next_batch(&mut self) -> Option<Vec<ThingData>> {
+        let (task_id, result) = match self.fetchset.join_next_with_id().await? {
+            Ok((task_id, result)) => (task_id, result),
+            Err(err) => (err.id(), Err(Error::Job(err.to_string()))),
+        };
+        let ids = self.batches.remove(&task_id).unwrap_or_default();
+        match result {
+            Ok(things) => {
+                let things = things.into_iter().map(|thing| thing.data).collect::<Vec<_>>();
+                self.missing.extend(ids.into_iter()
+                    .filter(|id| !things.iter().any(|thing| thing.bgg_id == *id))
+                    .map(|bgg_id| MissingThing{bgg_id, reason: "no such Thing on BGG".to_string()})
+                );
+                Some(things)
+            }
+            Err(err) => {
+                debug!("error fetching Things {ids:?}: {err:?}");
+                let reason = err.to_string();
+                self.missing.extend(ids.into_iter().map(|bgg_id| MissingThing{bgg_id, reason: reason.clone()}));
+                Some(vec![])
+            }
+        }
+    }
+}
 
-    let mut reader = Reader::from_str(&text);
-    reader.config_mut().trim_text(true);
+/// Whose fault an error document from BGG says it is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BggErrorKind {
+    /// What we asked for was no good, e.g. "Invalid id(s) supplied": it's down to our client's input
+    InvalidRequest,
+    /// Anything else, like "Rate limit exceeded.": it's down to BGG
+    Upstream,
+}
+
+impl BggErrorKind {
+    fn of(messages: &[String]) -> Self {
+        if messages.iter().any(|message| message.to_lowercase().contains("invalid")) {
+            Self::InvalidRequest
+        } else {
+            Self::Upstream
+        }
+    }
+}
+
+fn is_error(tag: &BytesStart) -> bool {
+    matches!(tag.local_name().as_ref(), b"error" | b"errors")
+}
+
+/// Reads the messages out of an error document BGG sent instead of what we asked for,
+/// e.g. `<error><message>Invalid id</message></error>`, or several of those inside `<errors>`.
+fn bgg_error(reader: &mut XmlReader<'_>, tag: &BytesStart) -> Error {
+    let mut messages = vec![];
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(inner)) if inner.local_name().as_ref() == b"message" => {
+                match reader.read_text(&inner) {
+                    Ok(message) => messages.push(message.trim().to_string()),
+                    Err(err) => return err,
+                }
+            }
+            Ok(Event::End(end)) if end.name() == tag.name() => break,
+            Ok(Event::Eof) => break,
+            Ok(_) => (),
+            Err(err) => return err,
+        }
+    }
+    Error::Bgg(BggErrorKind::of(&messages), messages.join("; "))
+}
+
+/// Reads the items out of a BGG search response, in the order BGG gave them.
+pub(crate) fn parse_search(xml: &[u8]) -> Result<Vec<SearchItem>, Error> {
+    let mut reader = XmlReader::new(xml);
 
     let mut items = vec![];
 
     loop {
-        match reader.read_event().unwrap() {
+        match reader.read_event()? {
             Event::Eof => break,
+            Event::Start(tag) if is_error(&tag) => return Err(bgg_error(&mut reader, &tag)),
             Event::Start(tag) if tag.local_name().as_ref() == "item".as_bytes() => {
                 let id = string_attr(&tag, "id");
                 let kind = string_attr(&tag, "type");
-                items.push(SearchItem{id, kind});
+                items.push(SearchItem{id, kind, ..Default::default()});
+            },
+            Event::Empty(tag) if tag.local_name().as_ref() == b"name" => {
+                if let Some(item) = items.last_mut() {
+                    let name = Some(string_attr(&tag, "value"));
+                    if string_attr(&tag, "type") == "primary" {
+                        item.name = name;
+                    } else {
+                        item.alternate_name = name;
+                    }
+                }
+            },
+            Event::Empty(tag) if tag.local_name().as_ref() == b"yearpublished" => {
+                if let Some(item) = items.last_mut() {
+                    item.year_published = string_attr(&tag, "value").parse().ok();
+                }
             },
             _ => ()
         }
     }
 
+    Ok(items)
+}
+
+/// Runs the search against BGG, pages the ranked results, and loads what it can from the cache.
+/// Fetches for the remaining Things are started, but not waited on.
+pub(crate) async fn start_search(client: BggClient, db: &Pool<Postgres>, query: String, page: usize, per_page: usize, bgg_limit: usize) -> Result<PendingSearch, Error>
+{
+    let rz = client.get(&format!("/search?query={}", query)).await?;
+
+    let body = rz.bytes().await?;
+    let mut items = parse_search(&body)?;
+
+    let total = items.len();
+    let lc_query = query.to_lowercase();
+    items.sort_by_key(|item| item.relevance(&lc_query));
+    let items = items.into_iter()
+        .skip(page.saturating_sub(1).saturating_mul(per_page))
+        .take(per_page)
+        .collect::<Vec<_>>();
+
     let ids = items.iter().map(|item| item.id.clone()).collect::<Vec<_>>();
-    let mut things: Vec<_> = BggThing::get_for_bgg_ids(db, ids.clone())
+    let cached: Vec<_> = BggThing::get_for_bgg_ids(db, ids.clone())
         .await
         .map_err(mattak::Error::from)?
         .iter()
         .map(|record| record.data.clone())
         .collect();
-    debug!("cached: {:?}", things);
+    debug!("cached: {:?}", cached);
     let needed_ids = ids.iter().filter(|id| {
         let check = (*id).clone();
-        !things.iter().any(
+        !cached.iter().any(
             |item| item.bgg_id == *check)
     }).cloned().collect::<Vec<_>>();
     debug!("needed_ids: {needed_ids:?}");
+    // fetch_things counts the rest, as it finds out which of them BGG is known not to have
+    client.count_cache(cached.len(), 0);
+    metrics::counter!("search_cache_hits_total").increment(cached.len() as u64);
+    metrics::counter!("search_cache_misses_total").increment(needed_ids.len() as u64);
 
     let mut batch_index = 0;
 
@@ -83,76 +370,134 @@ pub(crate) async fn search(client: Client, db: &Pool<Postgres>, query: String, b
     });
 
     let mut fetchset = JoinSet::new(bgg_limit);
+    let mut batches = HashMap::new();
     for (count, id_batch) in batch_iter.enumerate() {
         let our_client = client.clone();
         let our_db = db.clone();
+        let our_ids = id_batch.clone();
         debug!("spawning fetch job {count}: {id_batch:?}");
-        fetchset.spawn(async move {
-            match fetch_things(our_client, our_db, id_batch).await {
-                Ok(thing) => Some(thing),
-                Err(err) => {
-                    debug!("error fetching Thing: {err:?}");
-                    None
-                },
-            }
+        let handle = fetchset.spawn(async move {
+            fetch_things(our_client, our_db, our_ids).await
         });
+        batches.insert(handle.id(), id_batch);
         debug!("spawned  fetch job {count}");
     }
     debug!("spawned all fetch jobs");
 
-    while let Some(maybe_things) = fetchset.join_next().await {
-        if let Ok(Some(fetched_things)) = maybe_things {
-            for thing in fetched_things {
-                things.push(thing.data.clone());
-            }
-        }
-    }
-
-    Ok((items, things))
-
+    Ok(PendingSearch{total, items, cached, missing: vec![], fetchset, batches})
 }
 
 
-pub(crate) async fn fetch_things(client: Client, db: Pool<Postgres>, bgg_ids: Vec<String>) -> Result<Vec<BggThing<NoId>>, Error> {
+/// Fetches Things from BGG and stores them.
+/// Ids BGG recently had no Thing for aren't asked about again; ids it doesn't return now are remembered as such.
+/// For the audit log, the former count as cache hits and the rest as misses.
+pub(crate) async fn fetch_things(client: BggClient, db: Pool<Postgres>, bgg_ids: Vec<String>) -> Result<Vec<BggThing<NoId>>, Error> {
+    let missing = db::known_missing(&db, &bgg_ids).await.map_err(mattak::Error::from)?;
+    let bgg_ids = bgg_ids.into_iter().filter(|id| !missing.contains(id)).collect::<Vec<_>>();
+    client.count_cache(missing.len(), bgg_ids.len());
+    if bgg_ids.is_empty() {
+        debug!("ID: {missing:?} known to be missing");
+        return Ok(vec![]);
+    }
+
     debug!("ID: {bgg_ids:?} Fetching thing data");
-    let url = format!("{XMLAPI2}/thing?id={}", bgg_ids.join(","));
+    metrics::histogram!("bgg_thing_batch_size").record(bgg_ids.len() as f64);
+    let path = format!("/thing?id={}", bgg_ids.join(","));
     let mut pause = Duration::from_millis(500);
     let maxwait = Duration::from_secs(30);
 
+    let mut retrying = false;
     let rz = loop {
-        let rz = client.get(&url).send().await?;
+        let rz = if retrying { client.get_again(&path).await? } else { client.get(&path).await? };
         let status = rz.status();
         debug!("ID: {bgg_ids:?} Response status: {status:?}");
         debug!("ID: {bgg_ids:?} Response headers: {:?}", rz.headers());
-        if status.is_success() {
-            break rz;
-        }
-        if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        // BGG answers 202 with a "try again later" message when it's queued the request, rather than served it
+        if status == StatusCode::ACCEPTED || status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
             debug!("ID: {bgg_ids:?} Response body: {}", rz.text().await?);
+            if pause > maxwait {
+                debug!("ID {bgg_ids:?} wait would be {pause:?}, giving up");
+                metrics::counter!("bgg_thing_fetch_giving_up_total", "status" => status.as_u16().to_string()).increment(1);
+                return Err(Error::GivingUp(status));
+            }
             debug!("ID {bgg_ids:?} Waiting {pause:?} and retrying");
+            metrics::counter!("bgg_thing_fetch_retries_total", "status" => status.as_u16().to_string()).increment(1);
             sleep(pause).await;
+            retrying = true;
             pause = pause.mul_f32(rand::random::<f32>() + 1.5 );
             debug!("ID {bgg_ids:?} next retry will be {pause:?}");
             continue;
         }
-        if pause > maxwait {
-            debug!("ID {bgg_ids:?} new wait would be {pause:?}, giving up");
-            return Err(Error::GivingUp(status));
+        if status.is_success() {
+            break rz;
         }
         return Err(Error::Upstream(status));
     };
 
-    let text = rz.text().await?;
+    let body = rz.bytes().await?;
+    let items = parse_things(&body).map_err(|err| match err {
+        Error::Parse(err) => (*err).requested(&bgg_ids).into(),
+        err => err,
+    })?;
+
+    for item in &items {
+        match item.add_new(&db).await {
+            Ok(_) => metrics::counter!("bgg_things_stored_total").increment(1),
+            Err(err) => {
+                metrics::counter!("bgg_thing_store_failures_total").increment(1);
+                warn!("error storing Thing {}: {err:?}", item.data.bgg_id);
+            }
+        }
+        debug!("item: {item:?}");
+    }
+
+    let unknown = bgg_ids.iter()
+        .filter(|id| !items.iter().any(|item| item.data.bgg_id == **id))
+        .cloned()
+        .collect::<Vec<_>>();
+    if !unknown.is_empty() {
+        debug!("ID {unknown:?} not returned by BGG");
+        if let Err(err) = db::record_missing(&db, &unknown).await {
+            warn!("error recording missing Things {unknown:?}: {err:?}");
+        }
+    }
+
+    debug!("ID {bgg_ids:?} fetched: {}", items.len());
+
+    Ok(items)
+}
+
+/// Fetches and stores Things in the background, in batches, with nobody waiting on them.
+pub(crate) fn prefetch(client: BggClient, db: Pool<Postgres>, bgg_ids: Vec<String>, bgg_limit: usize) {
+    task::spawn(async move {
+        let mut fetchset = JoinSet::new(bgg_limit);
+        for batch in bgg_ids.chunks(BGG_THING_BATCH_SIZE) {
+            fetchset.spawn(fetch_things(client.clone(), db.clone(), batch.to_vec()));
+        }
 
+        let mut fetched = 0;
+        while let Some(result) = fetchset.join_next().await {
+            match result {
+                Ok(Ok(things)) => fetched += things.len(),
+                Ok(Err(err)) => warn!("prefetch batch failed: {err:?}"),
+                Err(err) => warn!("prefetch job failed: {err:?}"),
+            }
+        }
+        debug!("prefetched {fetched} of {} Things", bgg_ids.len());
+    });
+}
+
+/// Reads the Things out of a BGG thing (or family) response.
+pub(crate) fn parse_things(xml: &[u8]) -> Result<Vec<BggThing<NoId>>, Error> {
     let mut items = Vec::<BggThing::<NoId>>::new();
-    let mut reader = Reader::from_str(&text);
-    reader.config_mut().trim_text(true);
+    let mut reader = XmlReader::new(xml);
     loop {
-        match reader.read_event().unwrap() {
+        match reader.read_event()? {
             Event::Start(tag) if tag.local_name().as_ref() == b"items" => break,
+            Event::Start(tag) if is_error(&tag) => return Err(bgg_error(&mut reader, &tag)),
             Event::Start(tag) => {
                 debug!("ignoring tag: {tag:?}");
-                reader.read_to_end(tag.to_end().into_owned().name())?;
+                reader.skip(&tag)?;
             }
             ev@(Event::Empty(_) | Event::Text(_) | Event::CData(_) | Event::Comment(_) | Event::Decl(_)) => {
                 debug!("ignoring {ev:?}");
@@ -164,25 +509,17 @@ pub(crate) async fn fetch_things(client: Client, db: Pool<Postgres>, bgg_ids: Ve
         }
     }
     loop {
-        match reader.read_event().unwrap() {
+        match reader.read_event()? {
             Event::Eof => return Err(Error::MalformedResponse),
             Event::Start(tag) if tag.local_name().as_ref() == "item".as_bytes() => {
                 let id = string_attr(&tag, "id");
                 let kind = string_attr(&tag, "type");
                 let item = BggThing::extract_xml(&mut reader, id, kind, tag.to_end().into_owned().name())?;
-                match item.add_new(&db).await {
-                    Ok(_) => (),
-                    Err(err) => {
-                        debug!("error storing Thing: {err:?}");
-                    }
-                }
-                //reader.read_to_end(tag.to_end().into_owned().name())?;
-                debug!("item: {item:?}");
                 items.push(item);
             },
             Event::Start(tag) => {
                 debug!("OPEN {tag:?}");
-                reader.read_to_end(tag.to_end().into_owned().name())?;
+                reader.skip(&tag)?;
             },
             Event::End(tag) if tag.local_name().as_ref() == b"items" => {
                 break
@@ -196,36 +533,34 @@ pub(crate) async fn fetch_things(client: Client, db: Pool<Postgres>, bgg_ids: Ve
         }
     };
 
-    debug!("ID {bgg_ids:?} fetched: {}", items.len());
-
     Ok(items)
 }
 
 impl BggThing<NoId> {
-    pub fn extract_xml(reader: &mut Reader<&[u8]>, bgg_id: String, kind: String, until: QName<'_>) -> Result<BggThing<NoId>, Error> {
+    pub fn extract_xml(reader: &mut XmlReader<'_>, bgg_id: String, kind: String, until: QName<'_>) -> Result<BggThing<NoId>, Error> {
         use quick_xml::events::Event;
         let data = ThingData{bgg_id, kind, ..Default::default()};
         let mut item = BggThing{data, ..Default::default()};
         loop {
-            match reader.read_event().unwrap() {
+            match reader.read_event()? {
                 Event::Eof => break, //XXX error?
                 Event::Start(tag) => {
                     match tag.name().as_ref() {
                         b"thumbnail" => {
-                            let th = reader.read_text(tag.to_end().into_owned().name())?;
+                            let th = reader.read_text(&tag)?;
                             item.data.thumbnail = Some(th.into());
                         },
                         b"image" => {
-                            let img = reader.read_text(tag.to_end().into_owned().name())?;
+                            let img = reader.read_text(&tag)?;
                             item.data.image = Some(img.into());
                         },
                         b"description" => {
-                            let description = reader.read_text(tag.to_end().into_owned().name())?;
+                            let description = reader.read_text(&tag)?;
                             item.data.description = Some(description.into());
                         }
                         _ => {
                             debug!("ignoring tag: {tag:?}");
-                            reader.read_to_end(tag.to_end().into_owned().name())?;
+                            reader.skip(&tag)?;
                         }
                     }
                 }
@@ -240,22 +575,29 @@ impl BggThing<NoId> {
                             }
                         }
                         b"yearpublished" => {
-                            item.data.year_published = Some(string_attr(&tag, "value").parse()?);
+                            item.data.year_published = int_value(&tag, &item.data.bgg_id);
                         }
                         b"minplaytime" => {
-                            item.data.min_duration = Some(string_attr(&tag, "value").parse()?);
+                            item.data.min_duration = int_value(&tag, &item.data.bgg_id);
                         }
                         b"maxplaytime" => {
-                            item.data.max_duration = Some(string_attr(&tag, "value").parse()?);
+                            item.data.max_duration = int_value(&tag, &item.data.bgg_id);
                         }
                         b"playingtime" => {
-                            item.data.duration = Some(string_attr(&tag, "value").parse()?);
+                            item.data.duration = int_value(&tag, &item.data.bgg_id);
                         }
                         b"minplayers" => {
-                            item.data.min_players = Some(string_attr(&tag, "value").parse()?);
+                            item.data.min_players = int_value(&tag, &item.data.bgg_id);
                         }
                         b"maxplayers" => {
-                            item.data.max_players = Some(string_attr(&tag, "value").parse()?);
+                            item.data.max_players = int_value(&tag, &item.data.bgg_id);
+                        }
+                        b"minage" => {
+                            item.data.min_age = int_value(&tag, &item.data.bgg_id);
+                        }
+                        // Video games have a release date (e.g. "2001-11-15") where others have a year
+                        b"releasedate" if item.data.year_published.is_none() => {
+                            item.data.year_published = string_attr(&tag, "value").get(..4).and_then(|year| year.parse().ok());
                         }
                         b"link" => {
                             match string_attr(&tag, "type").as_ref() {
@@ -279,31 +621,20 @@ impl BggThing<NoId> {
                                     let name = string_attr(&tag, "value");
                                     item.links.publishers.push(LinkData { bgg_id, name });
                                 }
+                                "boardgamemechanic" => {
+                                    let bgg_id = string_attr(&tag, "id");
+                                    let name = string_attr(&tag, "value");
+                                    item.links.mechanics.push(LinkData { bgg_id, name });
+                                }
+                                ty if TAG_LINK_TYPES.contains(&ty) => {
+                                    let bgg_id = string_attr(&tag, "id");
+                                    let name = string_attr(&tag, "value");
+                                    item.links.tags.push(TagData { link_type: ty.to_string(), bgg_id, name });
+                                }
                                 ty => debug!("ignoring unknown link type: {}", ty.to_string())
-                                /*
-                                * Some of this list (and some of the above)
-                                * should be "tags" instead of links
-                                * criteria to consider:
-                                * BGG's API doesn't have data for it (i.e. we can't get a list of
-                                * games from the link)
-                                * The think itself doesn't have (enough, meaningful) data beyond a
-                                * name and an id.
-                                * A "tags" table then handles all of those.
-                                *
-                                */
-                                // rpg
-                                // rpggenre
-                                // rpgseries
-                                // rpgcategory
-                                // rpgpublisher
-                                // rpgdesigner
-                                // rpgartist
-                                // rpgproducer
-                                // boardgamemechanic
                                 // boardgameexpansion
                                 // boardgamecompilation
                                 // boardgameartist
-                                // boardgameaccessory
                                 // boardgameimplementation
                             }
                         }
@@ -317,3 +648,341 @@ impl BggThing<NoId> {
         Ok(item)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(bgg_id: &str, name: &str) -> LinkData {
+        LinkData{bgg_id: bgg_id.to_string(), name: name.to_string()}
+    }
+
+    fn only_thing(xml: &[u8]) -> BggThing<NoId> {
+        let mut things = parse_things(xml).expect("fixture parses");
+        assert_eq!(things.len(), 1);
+        things.remove(0)
+    }
+
+    #[test]
+    fn chess() {
+        let thing = only_thing(include_bytes!("../testdata/thing-chess.xml"));
+        let data = &thing.data;
+
+        assert_eq!(data.bgg_id, "171");
+        assert_eq!(data.kind, "boardgame");
+        assert_eq!(data.name.as_deref(), Some("Chess"));
+        assert!(data.thumbnail.as_deref().unwrap().ends_with("/pic8785991.jpg"));
+        assert!(data.image.as_deref().unwrap().ends_with("/pic8785991.jpg"));
+        assert!(data.description.as_deref().unwrap().starts_with("Chess is a two-player, abstract strategy board game"));
+
+        assert_eq!(data.altnames.len(), 164);
+        assert_eq!(data.altnames.first().map(String::as_str), Some("2020 Battle for The White House Chess Set"));
+        assert!(data.altnames.contains(&"Chess King's 48".to_string()));
+        assert!(data.altnames.contains(&"Cờ Vua".to_string()));
+        assert_eq!(data.altnames.last().map(String::as_str), Some("체스"));
+
+        assert_eq!(data.year_published, Some(1475));
+        assert_eq!(data.min_players, Some(2));
+        assert_eq!(data.max_players, Some(2));
+        assert_eq!(data.duration, Some(0));
+        assert_eq!(data.min_duration, Some(0));
+        assert_eq!(data.max_duration, Some(0));
+        assert_eq!(data.min_age, Some(6));
+
+        let links = &thing.links;
+        assert_eq!(links.categories, vec![link("1009", "Abstract Strategy")]);
+        assert_eq!(links.designers, vec![link("3", "(Uncredited)")]);
+        assert_eq!(links.mechanics, vec![
+            link("2676", "Grid Movement"),
+            link("2846", "Once-Per-Game Abilities"),
+            link("2946", "Pattern Movement"),
+            link("2940", "Square Grid"),
+            link("2861", "Static Capture"),
+            link("2884", "Sudden Death Ending"),
+        ]);
+        assert_eq!(links.families.len(), 65);
+        assert_eq!(links.families.first(), Some(&link("26432", "Category: Combinatorial")));
+        assert_eq!(links.publishers.len(), 203);
+        assert_eq!(links.publishers.last(), Some(&link("57418", "Киевпластмасс")));
+        assert_eq!(links.tags.len(), 1);
+        assert_eq!(links.tags[0].link_type, "boardgameaccessory");
+    }
+
+    #[test]
+    fn duhr() {
+        let thing = only_thing(include_bytes!("../testdata/thing-duhr.xml"));
+        let data = &thing.data;
+
+        assert_eq!(data.bgg_id, "246508");
+        assert_eq!(data.kind, "boardgame");
+        assert_eq!(data.name.as_deref(), Some("Dȗhr: The Lesser Houses"));
+        assert!(data.thumbnail.as_deref().unwrap().ends_with("/pic4096709.png"));
+        assert!(data.image.as_deref().unwrap().ends_with("/pic4096709.png"));
+        assert!(data.description.as_deref().unwrap().starts_with("The monolithic city-state of D"));
+        assert!(data.altnames.is_empty());
+
+        assert_eq!(data.year_published, Some(2018));
+        assert_eq!(data.min_players, Some(4));
+        assert_eq!(data.max_players, Some(6));
+        assert_eq!(data.duration, Some(30));
+        assert_eq!(data.min_duration, Some(30));
+        assert_eq!(data.max_duration, Some(30));
+        assert_eq!(data.min_age, Some(14));
+
+        // The nested <versions> item, and its publisher link, are not part of the Thing
+        let links = &thing.links;
+        assert_eq!(links.categories, vec![
+            link("1023", "Bluffing"),
+            link("1002", "Card Game"),
+            link("1010", "Fantasy"),
+            link("1026", "Negotiation"),
+        ]);
+        assert_eq!(links.mechanics, vec![
+            link("2040", "Hand Management"),
+            link("2686", "Take That"),
+            link("2008", "Trading"),
+            link("2015", "Variable Player Powers"),
+        ]);
+        assert_eq!(links.designers, vec![link("73358", "Jenna Felli")]);
+        assert_eq!(links.publishers, vec![link("26624", "Devious Weasel Games")]);
+        assert!(links.families.is_empty());
+    }
+
+    #[test]
+    fn family() {
+        let thing = only_thing(include_bytes!("../testdata/family-usa.xml"));
+        let data = &thing.data;
+
+        assert_eq!(data.bgg_id, "14835");
+        assert_eq!(data.kind, "boardgamefamily");
+        assert_eq!(data.name.as_deref(), Some("Country: USA"));
+        assert_eq!(data.altnames, vec!["Country: United States of America".to_string()]);
+        assert!(data.description.as_deref().unwrap().starts_with("Games (expansions, promos, etc.) featuring the United States"));
+        assert_eq!(data.year_published, None);
+        assert_eq!(data.min_players, None);
+        assert_eq!(data.min_age, None);
+
+        let links = &thing.links;
+        assert_eq!(links.families.len(), 1993);
+        assert_eq!(links.families.first(), Some(&link("442527", "10 Days in the National Parks")));
+        assert!(links.families.contains(&link("377208", "1758 Fort Carillon: Abercromby's Fumble at Ticonderoga")));
+        assert!(links.categories.is_empty());
+        assert!(links.designers.is_empty());
+        assert!(links.publishers.is_empty());
+        assert!(links.mechanics.is_empty());
+    }
+
+    #[test]
+    fn malformed() {
+        let xml = br#"<items><item type="boardgame" id="7"><name type="primary" value="Seven"/><description>oops</thumbnail></item></items>"#;
+        match parse_things(xml) {
+            Err(Error::Parse(err)) => {
+                assert_eq!(err.path, "items/item[7]/description");
+                assert_eq!(err.bgg_ids, vec!["7".to_string()]);
+                assert!(err.snippet.contains("oops</thumbnail>"));
+            }
+            other => panic!("expected a parse error, got {:?}", other.map(|things| things.len())),
+        }
+
+        match parse_search(b"<items><item id=\"1\"></items>") {
+            Err(Error::Parse(err)) => assert_eq!(err.path, "items/item[1]"),
+            other => panic!("expected a parse error, got {:?}", other.map(|items| items.len())),
+        }
+    }
+
+    fn tag(link_type: &str, bgg_id: &str, name: &str) -> TagData {
+        TagData{link_type: link_type.to_string(), bgg_id: bgg_id.to_string(), name: name.to_string()}
+    }
+
+    #[test]
+    fn other_kinds() {
+        let xml = br#"<items>
+            <item type="rpgitem" id="45210">
+                <name type="primary" value="Dungeon Master's Guide"/>
+                <yearpublished value="2003"/>
+                <link type="rpggenre" id="100" value="Fantasy"/>
+                <link type="rpgseries" id="200" value="Core Rules"/>
+                <link type="rpgdesigner" id="300" value="Monte Cook"/>
+                <link type="rpg" id="400" value="Dungeons &amp; Dragons"/>
+            </item>
+            <item type="videogame" id="69327">
+                <name type="primary" value="Tetris"/>
+                <releasedate value="1984-06-06"/>
+                <minplayers value="1"/>
+                <maxplayers value="1"/>
+                <link type="videogameplatform" id="11" value="Game Boy"/>
+                <link type="videogamegenre" id="12" value="Puzzle"/>
+            </item>
+            <item type="boardgameaccessory" id="3817">
+                <name type="primary" value="Chess Clock"/>
+                <yearpublished value="1998"/>
+                <link type="boardgameaccessory" id="171" value="Chess" inbound="true"/>
+                <link type="boardgamepublisher" id="57" value="DGT"/>
+            </item>
+        </items>"#;
+        let things = parse_things(xml).expect("parses");
+        assert_eq!(things.iter().map(|thing| thing.data.kind.as_str()).collect::<Vec<_>>(), vec!["rpgitem", "videogame", "boardgameaccessory"]);
+
+        let rpg = &things[0];
+        assert_eq!(rpg.data.year_published, Some(2003));
+        assert_eq!(rpg.links.tags, vec![
+            tag("rpggenre", "100", "Fantasy"),
+            tag("rpgseries", "200", "Core Rules"),
+            tag("rpgdesigner", "300", "Monte Cook"),
+            tag("rpg", "400", "Dungeons & Dragons"),
+        ]);
+
+        let video = &things[1];
+        assert_eq!(video.data.year_published, Some(1984));
+        assert_eq!(video.data.max_players, Some(1));
+        assert_eq!(video.links.tags, vec![tag("videogameplatform", "11", "Game Boy"), tag("videogamegenre", "12", "Puzzle")]);
+
+        let accessory = &things[2];
+        assert_eq!(accessory.links.tags, vec![tag("boardgameaccessory", "171", "Chess")]);
+        assert_eq!(accessory.links.publishers, vec![link("57", "DGT")]);
+    }
+
+    #[test]
+    fn error_documents() {
+        let xml = br#"<?xml version="1.0" encoding="utf-8"?><error><message>Invalid id(s) supplied</message></error>"#;
+        match parse_things(xml) {
+            Err(Error::Bgg(kind, message)) => {
+                assert_eq!(kind, BggErrorKind::InvalidRequest);
+                assert_eq!(message, "Invalid id(s) supplied");
+            }
+            other => panic!("expected a BGG error, got {:?}", other.map(|things| things.len())),
+        }
+
+        let xml = br#"<errors><error><message>Rate limit exceeded.</message></error><error><message>Try later</message></error></errors>"#;
+        match parse_search(xml) {
+            Err(Error::Bgg(kind, message)) => {
+                assert_eq!(kind, BggErrorKind::Upstream);
+                assert_eq!(message, "Rate limit exceeded.; Try later");
+            }
+            other => panic!("expected a BGG error, got {:?}", other.map(|items| items.len())),
+        }
+    }
+
+    #[test]
+    fn odd_numbers() {
+        let xml = br#"<items>
+            <item type="boardgame" id="8">
+                <name type="primary" value="Eight"/>
+                <yearpublished value=""/>
+                <minplayers value="two"/>
+                <maxplayers value=" 4 "/>
+                <playingtime value="90"/>
+            </item>
+            <item type="boardgame" id="9"><minage value="12+"/><minplaytime value="15"/></item>
+        </items>"#;
+        let things = parse_things(xml).expect("odd values don't stop parsing");
+        assert_eq!(things.len(), 2);
+
+        let data = &things[0].data;
+        assert_eq!(data.name.as_deref(), Some("Eight"));
+        assert_eq!(data.year_published, None);
+        assert_eq!(data.min_players, None);
+        assert_eq!(data.max_players, Some(4));
+        assert_eq!(data.duration, Some(90));
+
+        let data = &things[1].data;
+        assert_eq!(data.min_age, None);
+        assert_eq!(data.min_duration, Some(15));
+    }
+
+    #[test]
+    fn search() {
+        let items = parse_search(include_bytes!("../testdata/search-house.xml")).expect("fixture parses");
+
+        assert_eq!(items.len(), 1332);
+        assert_eq!(items.iter().filter(|item| item.year_published.is_some()).count(), 1099);
+        assert_eq!(items.iter().filter(|item| item.kind == "rpgitem").count(), 490);
+        assert_eq!(items.iter().filter(|item| item.kind == "videogame").count(), 142);
+
+        let first = &items[0];
+        assert_eq!(first.id, "171");
+        assert_eq!(first.kind, "boardgame");
+        assert_eq!(first.name, None);
+        assert_eq!(first.alternate_name.as_deref(), Some("2020 Battle for The White House Chess Set"));
+        assert_eq!(first.year_published, Some(1475));
+
+        let unpublished = items.iter().find(|item| item.id == "54173").unwrap();
+        assert_eq!(unpublished.name.as_deref(), Some("Build A House"));
+        assert_eq!(unpublished.year_published, None);
+
+        let last = items.last().unwrap();
+        assert_eq!(last.id, "166299");
+        assert_eq!(last.kind, "videogame");
+        assert_eq!(last.name.as_deref(), Some("WWF In Your House"));
+        assert_eq!(last.year_published, None);
+    }
+
+    #[test]
+    fn search_ranking() {
+        let items = parse_search(include_bytes!("../testdata/search-house.xml")).expect("fixture parses");
+        let ranks = items.iter().map(|item| item.relevance("house")).collect::<Vec<_>>();
+
+        // A third item is called just "House", but only as an alternate name
+        assert_eq!(ranks.iter().filter(|rank| **rank == 0).count(), 2);
+        assert_eq!(ranks.iter().filter(|rank| **rank == 3).count(), 51);
+        assert_eq!(items.iter().find(|item| item.id == "54173").unwrap().relevance("house"), 2);
+        // Chess only matched on an alternate name, so it goes after every match on a primary one
+        assert_eq!(items.iter().find(|item| item.id == "171").unwrap().relevance("house"), 3);
+
+        let mut ranked = items.iter().collect::<Vec<_>>();
+        ranked.sort_by_key(|item| item.relevance("house"));
+        assert!(ranked[..2].iter().all(|item| item.name.as_deref() == Some("House")));
+        let on_primary = |item: &SearchItem| item.name.as_ref().is_some_and(|name| name.to_lowercase().contains("house"));
+        let first_alternate = ranked.iter().position(|item| item.name.is_none()).expect("an alternate-only match");
+        assert!(ranked[..first_alternate].iter().all(|item| on_primary(item)));
+        assert!(!ranked[first_alternate..].iter().any(|item| on_primary(item)));
+    }
+
+    /// Tests against a database, as well as the fake BGG
+    mod db {
+        use clap::Parser;
+
+        use super::super::*;
+        use crate::{audit::Tally, auth::Identity, db::{testing::TestSchema, upstream_usage, UpstreamUsage}, fake_bgg, quota::Account};
+
+        /// A client for a fake BGG, started with these extra arguments
+        async fn fake_bgg(args: &[&str]) -> BggClient {
+            let config = fake_bgg::Config::parse_from(["fake-bgg", "--local-addr", "127.0.0.1:0"].iter().chain(args));
+            let (listener, app) = fake_bgg::bind(&config).await.expect("bind fake BGG");
+            let url = format!("http://{}/xmlapi2", listener.local_addr().expect("fake BGG address"));
+            tokio::spawn(async move { axum::serve(listener, app).await });
+            BggClient::new(reqwest::Client::new(), &url, Traffic::Live)
+        }
+
+        #[tokio::test]
+        async fn fetching_through_queued_requests() {
+            let schema = TestSchema::new().await;
+            let client = fake_bgg(&["--accepted-every", "2"]).await;
+
+            // The first request is answered; the second is queued, with a 202, and has to be asked again
+            for id in ["171", "246508"] {
+                let things = fetch_things(client.clone(), schema.pool.clone(), vec![id.to_string()]).await.expect("fetch things");
+                assert_eq!(things.iter().map(|thing| thing.data.bgg_id.as_str()).collect::<Vec<_>>(), vec![id]);
+            }
+
+            schema.drop().await;
+        }
+
+        #[tokio::test]
+        async fn retries_arent_counted_against_quotas() {
+            let schema = TestSchema::new().await;
+            let alice = Identity{authority: "example.com".to_string(), user: "alice".to_string()};
+            let client = fake_bgg(&["--rate-limit-every", "2"]).await
+                .on_behalf_of(Account::new(schema.pool.clone(), alice, Tally::default()));
+
+            // The second fetch is refused with a 429 at first, and has to be asked again
+            for id in ["171", "246508"] {
+                fetch_things(client.clone(), schema.pool.clone(), vec![id.to_string()]).await.expect("fetch things");
+            }
+            assert_eq!(upstream_usage(&schema.pool, "example.com", "alice").await.expect("usage"),
+                vec![UpstreamUsage{endpoint: "thing".to_string(), calls: 2}]);
+
+            schema.drop().await;
+        }
+    }
+}
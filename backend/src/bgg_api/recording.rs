@@ -0,0 +1,97 @@
+//! Recording and replaying our traffic with BGG.
+//!
+//! Each request is stored under its path and query, percent-encoded:
+//! the response body goes in NAME.xml, so recordings can be used as testdata fixtures,
+//! and the status and URL go alongside it in NAME.json.
+//! A request made more than once (e.g. when retrying) keeps only its last response.
+use std::path::{Path, PathBuf};
+
+use axum::{body::Bytes, http};
+use reqwest::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::Error;
+
+#[derive(Clone, Debug)]
+pub(crate) enum Traffic {
+    /// Just talk to BGG
+    Live,
+    /// Talk to BGG, and write every response into this directory
+    Record(PathBuf),
+    /// Don't talk to BGG: answer from the responses recorded in this directory
+    Replay(PathBuf),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Exchange {
+    request: String,
+    status: u16,
+}
+
+/// Percent-encodes everything but a few characters that are safe in file names,
+/// so that different requests can't end up with the same name.
+fn recording_name(path_and_query: &str) -> String {
+    path_and_query.trim_start_matches('/').bytes().map(|b| match b {
+        b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b',' | b'=' | b'_' => char::from(b).to_string(),
+        _ => format!("%{b:02X}"),
+    }).collect()
+}
+
+fn recording_paths(dir: &Path, path_and_query: &str) -> (PathBuf, PathBuf) {
+    let name = recording_name(path_and_query);
+    (dir.join(format!("{name}.json")), dir.join(format!("{name}.xml")))
+}
+
+fn recording_error(path_and_query: &str, err: impl std::fmt::Display) -> Error {
+    Error::Recording(format!("{path_and_query}: {err}"))
+}
+
+pub(super) async fn record(dir: &Path, path_and_query: &str, status: StatusCode, body: &Bytes) -> Result<(), Error> {
+    let (meta_path, body_path) = recording_paths(dir, path_and_query);
+    debug!("recording {path_and_query} to {body_path:?}");
+    let meta = serde_json::to_vec_pretty(&Exchange{request: path_and_query.to_string(), status: status.as_u16()})?;
+    tokio::fs::write(meta_path, meta).await.map_err(|e| recording_error(path_and_query, e))?;
+    tokio::fs::write(body_path, body).await.map_err(|e| recording_error(path_and_query, e))?;
+    Ok(())
+}
+
+pub(super) async fn replay(dir: &Path, path_and_query: &str) -> Result<Response, Error> {
+    let (meta_path, body_path) = recording_paths(dir, path_and_query);
+    debug!("replaying {path_and_query} from {body_path:?}");
+    let meta = tokio::fs::read(meta_path).await.map_err(|e| recording_error(path_and_query, e))?;
+    let exchange: Exchange = serde_json::from_slice(&meta)?;
+    let body = tokio::fs::read(body_path).await.map_err(|e| recording_error(path_and_query, e))?;
+    rebuild(StatusCode::from_u16(exchange.status).map_err(|e| recording_error(path_and_query, e))?, body.into())
+}
+
+/// Makes a reqwest Response out of a status and body we already have in hand
+pub(super) fn rebuild(status: StatusCode, body: Bytes) -> Result<Response, Error> {
+    let rz = http::Response::builder()
+        .status(status)
+        .body(body)
+        .map_err(|e| Error::Recording(e.to_string()))?;
+    Ok(rz.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn similar_requests_replay_separately() {
+        let dir = std::env::temp_dir().join(format!("bggapi-recording-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.expect("create recording dir");
+
+        let requests = ["/search?query=a b", "/search?query=a_b", "/search?query=a%20b"];
+        for request in requests {
+            record(&dir, request, StatusCode::OK, &Bytes::from(request)).await.expect("record");
+        }
+        for request in requests {
+            let body = replay(&dir, request).await.expect("replay").text().await.expect("body");
+            assert_eq!(body, request);
+        }
+
+        tokio::fs::remove_dir_all(&dir).await.expect("remove recording dir");
+    }
+}
@@ -0,0 +1,118 @@
+//! Reading BGG's XML, keeping track of where we are so that we can say so when it goes wrong.
+use std::borrow::Cow;
+
+use quick_xml::{events::{BytesStart, Event}, Reader};
+use tracing::warn;
+
+use super::string_attr;
+use crate::Error;
+
+/// How much of the response to log on either side of a parse error
+const SNIPPET_RADIUS: usize = 80;
+
+/// A BGG response we couldn't read as XML.
+#[derive(thiserror::Error, Debug)]
+#[error("unreadable XML from BGG at byte {position} in /{path} (BGG ids: {bgg_ids:?}): {source}")]
+pub struct ParseError {
+    pub position: u64,
+    /// The elements open at the point of failure, e.g. `items/item[171]/description`
+    pub path: String,
+    /// The ids of the items being read, or of those asked for
+    pub bgg_ids: Vec<String>,
+    pub snippet: String,
+    #[source]
+    pub source: quick_xml::Error,
+}
+
+impl ParseError {
+    /// Falls back to the ids we asked BGG for, if the failure wasn't inside any particular item
+    pub fn requested(mut self, bgg_ids: &[String]) -> Self {
+        if self.bgg_ids.is_empty() {
+            self.bgg_ids = bgg_ids.to_vec();
+        }
+        self
+    }
+}
+
+struct Element {
+    name: String,
+    bgg_id: Option<String>,
+}
+
+/// A quick_xml Reader over a whole response, which tracks the path of open elements.
+pub(crate) struct XmlReader<'a> {
+    xml: &'a [u8],
+    reader: Reader<&'a [u8]>,
+    path: Vec<Element>,
+}
+
+impl<'a> XmlReader<'a> {
+    pub fn new(xml: &'a [u8]) -> Self {
+        let mut reader = Reader::from_reader(xml);
+        reader.config_mut().trim_text(true);
+        Self{xml, reader, path: vec![]}
+    }
+
+    pub fn read_event(&mut self) -> Result<Event<'a>, Error> {
+        match self.reader.read_event() {
+            Ok(Event::Start(tag)) => {
+                self.path.push(Element{
+                    name: String::from_utf8_lossy(tag.name().as_ref()).into_owned(),
+                    bgg_id: tag.try_get_attribute("id").ok().flatten().map(|_| string_attr(&tag, "id")),
+                });
+                Ok(Event::Start(tag))
+            }
+            Ok(Event::End(tag)) => {
+                self.path.pop();
+                Ok(Event::End(tag))
+            }
+            Ok(ev) => Ok(ev),
+            Err(err) => Err(self.error(err)),
+        }
+    }
+
+    /// Skips past the end of the element `tag` opened.
+    pub fn skip(&mut self, tag: &BytesStart) -> Result<(), Error> {
+        match self.reader.read_to_end(tag.to_end().name()) {
+            Ok(_) => {
+                self.path.pop();
+                Ok(())
+            }
+            Err(err) => Err(self.error(err)),
+        }
+    }
+
+    /// Reads the text of the element `tag` opened, up to its end.
+    pub fn read_text(&mut self, tag: &BytesStart) -> Result<Cow<'a, str>, Error> {
+        match self.reader.read_text(tag.to_end().name()) {
+            Ok(text) => {
+                self.path.pop();
+                Ok(text)
+            }
+            Err(err) => Err(self.error(err)),
+        }
+    }
+
+    fn error(&self, source: quick_xml::Error) -> Error {
+        let position = match source {
+            quick_xml::Error::Syntax(_) | quick_xml::Error::IllFormed(_) => self.reader.error_position(),
+            _ => self.reader.buffer_position(),
+        };
+        let at = usize::try_from(position).unwrap_or(usize::MAX).min(self.xml.len());
+        let snippet = String::from_utf8_lossy(
+            &self.xml[at.saturating_sub(SNIPPET_RADIUS)..at.saturating_add(SNIPPET_RADIUS).min(self.xml.len())]
+        ).into_owned();
+        let path = self.path.iter()
+            .map(|el| match &el.bgg_id {
+                Some(id) => format!("{}[{id}]", el.name),
+                None => el.name.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        let bgg_ids = self.path.iter().filter_map(|el| el.bgg_id.clone()).collect();
+
+        let err = ParseError{position, path, bgg_ids, snippet, source};
+        warn!("{err}; near: {:?}", err.snippet);
+        err.into()
+    }
+}
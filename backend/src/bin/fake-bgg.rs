@@ -0,0 +1,22 @@
+//! Serves the stand-in for the BGG XML API 2 (see fake_bgg.rs) until it's stopped.
+//!
+//! Run the backend with BGG_API_URL=http://127.0.0.1:3002/xmlapi2 to use it,
+//! and this with `--help` for how to make it misbehave.
+use clap::Parser;
+use tracing_subscriber::{prelude::*, EnvFilter};
+
+#[path = "../fake_bgg.rs"]
+mod fake_bgg;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer())
+        .with(EnvFilter::from_default_env())
+        .init();
+
+    let (listener, app) = fake_bgg::bind(&fake_bgg::Config::parse()).await?;
+    tracing::info!("fake BGG listening on {}", listener.local_addr()?);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
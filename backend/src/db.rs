@@ -1,14 +1,35 @@
+use std::time::Instant;
+
 use chrono::{DateTime,Utc};
 use serde::{Serialize};
 
-use sqlx::{query, query_as, query_scalar, Acquire, Executor, Postgres};
+use sqlx::{postgres::{PgArguments, PgRow}, query, query::QueryScalar, query_as, query_scalar, Acquire, Executor, Postgres};
 use mattak::querymapping::{NoId, Error};
 use mattak_derives::id_type;
 // use tracing::debug;
 
 id_type!(ThingId(i32),IdForThing);
 
-#[derive(sqlx::FromRow, Default, Debug, Clone, Serialize)]
+/// Records how long it lived in the db_query_duration_seconds histogram, labeled with the query's name.
+/// Start one at the top of a query function to time the whole of it.
+struct QueryTimer {
+    query: &'static str,
+    started: Instant,
+}
+
+impl QueryTimer {
+    fn start(query: &'static str) -> Self {
+        Self{query, started: Instant::now()}
+    }
+}
+
+impl Drop for QueryTimer {
+    fn drop(&mut self) {
+        metrics::histogram!("db_query_duration_seconds", "query" => self.query).record(self.started.elapsed());
+    }
+}
+
+#[derive(sqlx::FromRow, Default, Debug, Clone, Serialize, PartialEq)]
 pub(crate) struct ThingData {
     pub bgg_id: String,
     pub kind: String,
@@ -23,6 +44,7 @@ pub(crate) struct ThingData {
     pub min_duration: Option<i32>,
     pub max_duration: Option<i32>,
     pub duration: Option<i32>,
+    pub min_age: Option<i32>,
 }
 
 #[derive(sqlx::FromRow, Default, Debug, Clone, Serialize)]
@@ -31,6 +53,8 @@ pub(crate) struct ThingLinks {
     pub families: Vec<LinkData>,
     pub designers: Vec<LinkData>,
     pub publishers: Vec<LinkData>,
+    pub mechanics: Vec<LinkData>,
+    pub tags: Vec<TagData>,
 }
 
 #[derive(sqlx::FromRow, Default, Debug, Clone, Serialize)]
@@ -59,23 +83,39 @@ impl<T: IdForThing> BggThing<T> {
 
 impl BggThing<NoId> {
 
+    /// Stores the Thing, or refreshes it if we already have it:
+    /// scalar columns, altnames and links are all replaced by what's here.
     pub async fn add_new<'a, DB>(&self, db: DB)
     -> Result<ThingId, Error>
 where DB: Acquire<'a, Database = Postgres> + 'a {
+        let _timer = QueryTimer::start("add_new");
         let mut tx = db.begin().await?;
         let data = &self.data;
         let id = query_scalar!(
             r#"insert into bgg_thing (
     "bgg_id", "kind", "name", "description", "thumbnail", "image",
-    "year_published", "min_players", "max_players", "min_duration", "max_duration", "duration"
-    ) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
-    on conflict do nothing
+    "year_published", "min_players", "max_players", "min_duration", "max_duration", "duration",
+    "min_age"
+    ) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+    on conflict (bgg_id) do update set
+    "kind" = excluded.kind, "name" = excluded.name, "description" = excluded.description,
+    "thumbnail" = excluded.thumbnail, "image" = excluded.image,
+    "year_published" = excluded.year_published, "min_players" = excluded.min_players, "max_players" = excluded.max_players,
+    "min_duration" = excluded.min_duration, "max_duration" = excluded.max_duration, "duration" = excluded.duration,
+    "min_age" = excluded.min_age,
+    "updated_at" = now(), "retreived_at" = now()
     returning id"#,
             data.bgg_id, data.kind, data.name, data.description, data.thumbnail, data.image,
             data.year_published, data.min_players, data.max_players, data.min_duration, data.max_duration, data.duration,
+            data.min_age,
         ).fetch_one(&mut *tx)
         .await?;
 
+        query!(
+            r#"delete from bgg_altname where "thing_id" = $1 and not ("name" = any($2::text[]))"#,
+            id, &self.data.altnames
+        ).execute(&mut *tx).await?;
+
         query!(
             r#"insert into bgg_altname ("thing_id", "name")
     select $1, name from unnest($2::text[]) as a(name) on conflict do nothing"#,
@@ -85,71 +125,115 @@ where DB: Acquire<'a, Database = Postgres> + 'a {
 
         let cis = self.links.categories.iter().map(|c| c.bgg_id.clone()).collect::<Vec<_>>();
         let cns = self.links.categories.iter().map(|c| c.name.clone()).collect::<Vec<_>>();
-        let category_ids = query_scalar!(
-            r#"insert into bgg_category ("bgg_id", "name")
-   select bgg_id, name from unnest($1::text[], $2::text[]) as a(bgg_id, name)
-   on conflict do nothing
-   returning id"#,
-            &cis, &cns
-        ).fetch_all(&mut *tx)
-        .await?;
-
         query!(
-            r#"insert into thing_category ("thing_id", "category_id")
-  select $1, category_id from unnest($2::integer[]) as a(category_id)
+            r#"with linked as (
+    insert into bgg_category ("bgg_id", "name")
+    select distinct on (bgg_id) bgg_id, name from unnest($2::text[], $3::text[]) as a(bgg_id, name)
+    on conflict (bgg_id) do update set name = excluded.name
+    returning id
+  ),
+  unlinked as (
+    delete from thing_category
+    where "thing_id" = $1 and "category_id" not in (select id from linked)
+  )
+  insert into thing_category ("thing_id", "category_id")
+  select $1, id from linked
   on conflict do nothing
-"#, id, &category_ids).execute(&mut *tx).await?;
+"#, id, &cis, &cns).execute(&mut *tx).await?;
 
         let fis = self.links.families.iter().map(|c| c.bgg_id.clone()).collect::<Vec<_>>();
         let fns = self.links.families.iter().map(|c| c.name.clone()).collect::<Vec<_>>();
-        let family_ids = query_scalar!(
-            r#"insert into bgg_family ("bgg_id", "name")
-   select bgg_id, name from unnest($1::text[], $2::text[]) as a(bgg_id, name)
-   on conflict do nothing
-   returning id"#,
-            &fis, &fns
-        ).fetch_all(&mut *tx)
-        .await?;
-
         query!(
-            r#"insert into thing_family ("thing_id", "family_id")
-  select $1, family_id from unnest($2::integer[]) as a(family_id)
+            r#"with linked as (
+    insert into bgg_family ("bgg_id", "name")
+    select distinct on (bgg_id) bgg_id, name from unnest($2::text[], $3::text[]) as a(bgg_id, name)
+    on conflict (bgg_id) do update set name = excluded.name
+    returning id
+  ),
+  unlinked as (
+    delete from thing_family
+    where "thing_id" = $1 and "family_id" not in (select id from linked)
+  )
+  insert into thing_family ("thing_id", "family_id")
+  select $1, id from linked
   on conflict do nothing
-"#, id, &family_ids).execute(&mut *tx).await?;
+"#, id, &fis, &fns).execute(&mut *tx).await?;
 
         let dis = self.links.designers.iter().map(|c| c.bgg_id.clone()).collect::<Vec<_>>();
         let dns = self.links.designers.iter().map(|c| c.name.clone()).collect::<Vec<_>>();
-        let designer_ids = query_scalar!(
-            r#"insert into bgg_designer ("bgg_id", "name")
-   select bgg_id, name from unnest($1::text[], $2::text[]) as a(bgg_id, name)
-   on conflict do nothing
-   returning id"#,
-            &dis, &dns
-        ).fetch_all(&mut *tx)
-        .await?;
-
         query!(
-            r#"insert into thing_designer ("thing_id", "designer_id")
-  select $1, designer_id from unnest($2::integer[]) as a(designer_id)
+            r#"with linked as (
+    insert into bgg_designer ("bgg_id", "name")
+    select distinct on (bgg_id) bgg_id, name from unnest($2::text[], $3::text[]) as a(bgg_id, name)
+    on conflict (bgg_id) do update set name = excluded.name
+    returning id
+  ),
+  unlinked as (
+    delete from thing_designer
+    where "thing_id" = $1 and "designer_id" not in (select id from linked)
+  )
+  insert into thing_designer ("thing_id", "designer_id")
+  select $1, id from linked
   on conflict do nothing
-"#, id, &designer_ids).execute(&mut *tx).await?;
+"#, id, &dis, &dns).execute(&mut *tx).await?;
 
         let pis = self.links.publishers.iter().map(|c| c.bgg_id.clone()).collect::<Vec<_>>();
         let pns = self.links.publishers.iter().map(|c| c.name.clone()).collect::<Vec<_>>();
-        let publisher_ids = query_scalar!(
-            r#"insert into bgg_publisher ("bgg_id", "name")
-   select bgg_id, name from unnest($1::text[], $2::text[]) as a(bgg_id, name)
-   on conflict do nothing
-   returning id"#,
-            &pis, &pns
-        ).fetch_all(&mut *tx)
-        .await?;
+        query!(
+            r#"with linked as (
+    insert into bgg_publisher ("bgg_id", "name")
+    select distinct on (bgg_id) bgg_id, name from unnest($2::text[], $3::text[]) as a(bgg_id, name)
+    on conflict (bgg_id) do update set name = excluded.name
+    returning id
+  ),
+  unlinked as (
+    delete from thing_publisher
+    where "thing_id" = $1 and "publisher_id" not in (select id from linked)
+  )
+  insert into thing_publisher ("thing_id", "publisher_id")
+  select $1, id from linked
+  on conflict do nothing
+"#, id, &pis, &pns).execute(&mut *tx).await?;
+
+        let mis = self.links.mechanics.iter().map(|c| c.bgg_id.clone()).collect::<Vec<_>>();
+        let mns = self.links.mechanics.iter().map(|c| c.name.clone()).collect::<Vec<_>>();
+        query!(
+            r#"with linked as (
+    insert into bgg_mechanic ("bgg_id", "name")
+    select distinct on (bgg_id) bgg_id, name from unnest($2::text[], $3::text[]) as a(bgg_id, name)
+    on conflict (bgg_id) do update set name = excluded.name
+    returning id
+  ),
+  unlinked as (
+    delete from thing_mechanic
+    where "thing_id" = $1 and "mechanic_id" not in (select id from linked)
+  )
+  insert into thing_mechanic ("thing_id", "mechanic_id")
+  select $1, id from linked
+  on conflict do nothing
+"#, id, &mis, &mns).execute(&mut *tx).await?;
 
+        let tts = self.links.tags.iter().map(|t| t.link_type.clone()).collect::<Vec<_>>();
+        let tis = self.links.tags.iter().map(|t| t.bgg_id.clone()).collect::<Vec<_>>();
+        let tns = self.links.tags.iter().map(|t| t.name.clone()).collect::<Vec<_>>();
         query!(
-            r#"insert into thing_publisher ("thing_id", "publisher_id")
-  select $1, publisher_id from unnest($2::integer[]) as a(publisher_id)
+            r#"with linked as (
+    insert into bgg_tag ("link_type", "bgg_id", "name")
+    select distinct on (link_type, bgg_id) link_type, bgg_id, name
+    from unnest($2::text[], $3::text[], $4::text[]) as a(link_type, bgg_id, name)
+    on conflict (link_type, bgg_id) do update set name = excluded.name
+    returning id
+  ),
+  unlinked as (
+    delete from thing_tag
+    where "thing_id" = $1 and "tag_id" not in (select id from linked)
+  )
+  insert into thing_tag ("thing_id", "tag_id")
+  select $1, id from linked
   on conflict do nothing
-"#, id, &publisher_ids).execute(&mut *tx).await?;
+"#, id, &tts, &tis, &tns).execute(&mut *tx).await?;
+
+        query!(r#"delete from bgg_missing_thing where "bgg_id" = $1"#, data.bgg_id).execute(&mut *tx).await?;
 
         tx.commit().await?;
 
@@ -163,13 +247,14 @@ impl BggThing<ThingId> {
 
     pub async fn get_for_bgg_ids<'a, DB>(db: DB, bgg_ids: Vec<String>) -> Result<Vec<Self>, Error>
 where DB: Executor<'a, Database = Postgres> + Copy + 'a {
+        let _timer = QueryTimer::start("get_for_bgg_ids");
         let mut list = Vec::with_capacity(bgg_ids.len());
 
         let mut ids_iter = bgg_ids.into_iter();
 
         loop {
             let batch_ids = ids_iter.by_ref().take(Self::MAX_IDS).collect::<Vec<String>>();
-            if batch_ids.len() == 0 {
+            if batch_ids.is_empty() {
                 break
             }
 
@@ -198,6 +283,16 @@ where DB: Executor<'a, Database = Postgres> + Copy + 'a {
                 select TP.thing_id as id, array_agg((P.bgg_id, P.name)::link) as links
                 from thing_publisher TP left join bgg_publisher P on P.id = TP.publisher_id
                 group by TP.thing_id
+            ),
+            M as (
+                select TM.thing_id as id, array_agg((M.bgg_id, M.name)::link) as links
+                from thing_mechanic TM left join bgg_mechanic M on M.id = TM.mechanic_id
+                group by TM.thing_id
+            ),
+            G as (
+                select TG.thing_id as id, array_agg((G.link_type, G.bgg_id, G.name)::tag) as tags
+                from thing_tag TG left join bgg_tag G on G.id = TG.tag_id
+                group by TG.thing_id
             )
             select
                 T.*,
@@ -205,13 +300,17 @@ where DB: Executor<'a, Database = Postgres> + Copy + 'a {
                 coalesce(C.links, array[]::link[]) as "categories",
                 coalesce(F.links, array[]::link[]) as "families",
                 coalesce(D.links, array[]::link[]) as "designers",
-                coalesce(P.links, array[]::link[]) as "publishers"
+                coalesce(P.links, array[]::link[]) as "publishers",
+                coalesce(M.links, array[]::link[]) as "mechanics",
+                coalesce(G.tags, array[]::tag[]) as "tags"
             from bgg_thing T
             left join N on N.id = T.id
             left join C on C.id = T.id
             left join F on F.id = T.id
             left join D on D.id = T.id
             left join P on P.id = T.id
+            left join M on M.id = T.id
+            left join G on G.id = T.id
             where T.bgg_id = any($1)
             "#)
                 .bind(&batch_ids)
@@ -224,8 +323,122 @@ where DB: Executor<'a, Database = Postgres> + Copy + 'a {
         Ok(list)
     }
 }
+/// Criteria for finding cached Things. Every field is optional; unset fields don't filter.
+#[derive(Default, Debug, Clone)]
+pub(crate) struct ThingFilter {
+    /// Things that can be played with this few players
+    pub min_players: Option<i32>,
+    /// Things that can be played with this many players
+    pub max_players: Option<i32>,
+    /// Things that take at least this many minutes
+    pub min_duration: Option<i32>,
+    /// Things that take no more than this many minutes
+    pub max_duration: Option<i32>,
+    pub year_from: Option<i32>,
+    pub year_to: Option<i32>,
+    /// Things suitable for players of this age
+    pub age: Option<i32>,
+    // The remainder are BGG ids of linked entities
+    pub category: Option<String>,
+    pub family: Option<String>,
+    pub designer: Option<String>,
+    pub mechanic: Option<String>,
+    pub publisher: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+pub(crate) enum ThingOrder {
+    #[default]
+    Name,
+    Year,
+    Duration,
+    Players,
+}
+
+impl ThingOrder {
+    fn column(&self) -> &'static str {
+        match self {
+            ThingOrder::Name => "T.name",
+            ThingOrder::Year => "T.year_published",
+            ThingOrder::Duration => "coalesce(T.duration, T.max_duration, T.min_duration)",
+            ThingOrder::Players => "T.max_players",
+        }
+    }
+}
+
+impl BggThing<ThingId> {
+    const FIND_CONDITIONS: &str = r#"
+        ($1::integer is null or T.min_players <= $1)
+        and ($2::integer is null or T.max_players >= $2)
+        and ($3::integer is null or coalesce(T.min_duration, T.duration) >= $3)
+        and ($4::integer is null or coalesce(T.max_duration, T.duration) <= $4)
+        and ($5::integer is null or T.year_published >= $5)
+        and ($6::integer is null or T.year_published <= $6)
+        and ($7::integer is null or T.min_age <= $7)
+        and ($8::text is null or exists (
+            select 1 from thing_category TC join bgg_category C on C.id = TC.category_id
+            where TC.thing_id = T.id and C.bgg_id = $8))
+        and ($9::text is null or exists (
+            select 1 from thing_family TF join bgg_family F on F.id = TF.family_id
+            where TF.thing_id = T.id and F.bgg_id = $9))
+        and ($10::text is null or exists (
+            select 1 from thing_designer TD join bgg_designer D on D.id = TD.designer_id
+            where TD.thing_id = T.id and D.bgg_id = $10))
+        and ($11::text is null or exists (
+            select 1 from thing_mechanic TM join bgg_mechanic M on M.id = TM.mechanic_id
+            where TM.thing_id = T.id and M.bgg_id = $11))
+        and ($12::text is null or exists (
+            select 1 from thing_publisher TP join bgg_publisher P on P.id = TP.publisher_id
+            where TP.thing_id = T.id and P.bgg_id = $12))
+    "#;
+
+    /// Finds cached Things matching the filter, returning the total number of matches
+    /// along with the requested slice of them.
+    pub async fn find<'a, DB>(db: DB, filter: &ThingFilter, order: ThingOrder, descending: bool, limit: i64, offset: i64)
+    -> Result<(i64, Vec<Self>), Error>
+where DB: Executor<'a, Database = Postgres> + Copy + 'a {
+        let _timer = QueryTimer::start("find");
+        let count_sql = format!("select count(*) from bgg_thing T where {}", Self::FIND_CONDITIONS);
+        let total: i64 = filter.bind(query_scalar(&count_sql))
+            .fetch_one(db)
+            .await?;
+
+        let direction = if descending { "desc" } else { "asc" };
+        let ids_sql = format!(
+            "select T.bgg_id from bgg_thing T where {} order by {} {direction} nulls last, T.id limit $13 offset $14",
+            Self::FIND_CONDITIONS, order.column()
+        );
+        let bgg_ids: Vec<String> = filter.bind(query_scalar(&ids_sql))
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(db)
+            .await?;
+
+        let mut things = Self::get_for_bgg_ids(db, bgg_ids.clone()).await?;
+        things.sort_by_key(|thing| bgg_ids.iter().position(|id| *id == thing.data.bgg_id));
+
+        Ok((total, things))
+    }
+}
+
+impl ThingFilter {
+    fn bind<'q, O>(&'q self, q: QueryScalar<'q, Postgres, O, PgArguments>) -> QueryScalar<'q, Postgres, O, PgArguments> {
+        q.bind(self.min_players)
+            .bind(self.max_players)
+            .bind(self.min_duration)
+            .bind(self.max_duration)
+            .bind(self.year_from)
+            .bind(self.year_to)
+            .bind(self.age)
+            .bind(&self.category)
+            .bind(&self.family)
+            .bind(&self.designer)
+            .bind(&self.mechanic)
+            .bind(&self.publisher)
+    }
+}
+
 // n-n with
-//   boardgamemechanic (hi Sarah!)
 //   boardgamefamily /family
 //   boardgameexpansion /thing
 //   boardgameaccessory /thing
@@ -234,13 +447,22 @@ where DB: Executor<'a, Database = Postgres> + Copy + 'a {
 //   boardgameartist 100% IDK
 //   boardgamepublisher 100% IDK
 //
-#[derive(Default, Serialize, Debug, sqlx::Type, Clone)]
+#[derive(Default, Serialize, Debug, sqlx::Type, sqlx::FromRow, Clone, PartialEq)]
 #[sqlx(type_name = "link")]
 pub(crate) struct LinkData {
     pub bgg_id: String,
     pub name: String,
 }
 
+/// A link we only know the type, id and name of: see bgg_api::TAG_LINK_TYPES
+#[derive(Default, Serialize, Debug, sqlx::Type, Clone, PartialEq)]
+#[sqlx(type_name = "tag")]
+pub(crate) struct TagData {
+    pub link_type: String,
+    pub bgg_id: String,
+    pub name: String,
+}
+
 id_type!(CategoryId(i32),IdForCategory);
 
 #[derive(Default, Serialize, Debug, Clone, sqlx::FromRow)]
@@ -284,3 +506,636 @@ pub(crate) struct BggPublisher<ID: IdForPublisher> {
     #[sqlx(flatten)]
     pub data: LinkData
 }
+
+id_type!(MechanicId(i32), IdForMechanic);
+
+#[derive(Default, Serialize, Debug, sqlx::FromRow, Clone)]
+pub(crate) struct BggMechanic<ID: IdForMechanic> {
+    pub id: ID,
+    pub created_at: DateTime<Utc>,
+
+    #[sqlx(flatten)]
+    pub data: LinkData
+}
+
+/// The things a BggThing links out to. Each kind has its own table,
+/// and its own join table from bgg_thing.
+pub(crate) trait LinkEntity: for<'r> sqlx::FromRow<'r, PgRow> + Send + Unpin {
+    const TABLE: &'static str;
+    const JOIN_TABLE: &'static str;
+    const JOIN_COLUMN: &'static str;
+
+    fn id(&self) -> i32;
+    fn data(&self) -> &LinkData;
+}
+
+macro_rules! link_entity {
+    ($entity:ident, $id:ident, $table:literal, $join_table:literal, $join_column:literal) => {
+        impl LinkEntity for $entity<$id> {
+            const TABLE: &'static str = $table;
+            const JOIN_TABLE: &'static str = $join_table;
+            const JOIN_COLUMN: &'static str = $join_column;
+
+            fn id(&self) -> i32 {
+                self.id.into()
+            }
+
+            fn data(&self) -> &LinkData {
+                &self.data
+            }
+        }
+    };
+}
+
+link_entity!(BggCategory, CategoryId, "bgg_category", "thing_category", "category_id");
+link_entity!(BggFamily, FamilyId, "bgg_family", "thing_family", "family_id");
+link_entity!(BggDesigner, DesignerId, "bgg_designer", "thing_designer", "designer_id");
+link_entity!(BggPublisher, PublisherId, "bgg_publisher", "thing_publisher", "publisher_id");
+link_entity!(BggMechanic, MechanicId, "bgg_mechanic", "thing_mechanic", "mechanic_id");
+
+pub(crate) async fn get_link<'a, L, DB>(db: DB, bgg_id: &str) -> Result<L, Error>
+where L: LinkEntity, DB: Executor<'a, Database = Postgres> + 'a {
+    let _timer = QueryTimer::start("get_link");
+    let sql = format!("select * from {} where bgg_id = $1", L::TABLE);
+    Ok(query_as(&sql).bind(bgg_id).fetch_one(db).await?)
+}
+
+/// Removes a link entity from the cache, unlinking it from its Things; returns whether there was one.
+pub(crate) async fn purge_link<'a, L, DB>(db: DB, bgg_id: &str) -> Result<bool, Error>
+where L: LinkEntity, DB: Executor<'a, Database = Postgres> + 'a {
+    let _timer = QueryTimer::start("purge_link");
+    let sql = format!("delete from {} where bgg_id = $1", L::TABLE);
+    Ok(query(&sql).bind(bgg_id).execute(db).await?.rows_affected() > 0)
+}
+
+/// Removes a tag from the cache, unlinking it from its Things; returns whether there was one.
+/// Tags' ids are only unique within their link type, so it takes both.
+pub(crate) async fn purge_tag<'a, DB>(db: DB, link_type: &str, bgg_id: &str) -> Result<bool, Error>
+where DB: Executor<'a, Database = Postgres> + 'a {
+    let _timer = QueryTimer::start("purge_tag");
+    Ok(query("delete from bgg_tag where link_type = $1 and bgg_id = $2")
+        .bind(link_type).bind(bgg_id).execute(db).await?.rows_affected() > 0)
+}
+
+/// Removes Things from the cache, along with their links to other entities.
+/// Returns the ids of the Things there were.
+pub(crate) async fn purge_things<'a, DB>(db: DB, bgg_ids: &[String]) -> Result<Vec<String>, Error>
+where DB: Acquire<'a, Database = Postgres> {
+    let _timer = QueryTimer::start("purge_things");
+    let mut tx = db.begin().await?;
+    query!(
+        r#"delete from bgg_altname where thing_id in (select id from bgg_thing where bgg_id = any($1))"#,
+        bgg_ids
+    ).execute(&mut *tx).await?;
+    let purged = query_scalar!(
+        r#"delete from bgg_thing where bgg_id = any($1) returning bgg_id"#,
+        bgg_ids
+    ).fetch_all(&mut *tx).await?;
+    tx.commit().await?;
+    Ok(purged)
+}
+
+/// Forgets that BGG had no Thing for these ids, so that they'll be asked for again.
+pub(crate) async fn forget_missing<'a, DB>(db: DB, bgg_ids: &[String]) -> Result<(), Error>
+where DB: Executor<'a, Database = Postgres> + 'a {
+    let _timer = QueryTimer::start("forget_missing");
+    query!(r#"delete from bgg_missing_thing where bgg_id = any($1)"#, bgg_ids).execute(db).await?;
+    Ok(())
+}
+
+/// How much is in the cache
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
+pub(crate) struct CacheCounts {
+    pub things: i64,
+    pub categories: i64,
+    pub families: i64,
+    pub designers: i64,
+    pub publishers: i64,
+    pub mechanics: i64,
+    pub tags: i64,
+    pub missing_things: i64,
+    pub oldest_retrieved: Option<DateTime<Utc>>,
+    pub newest_retrieved: Option<DateTime<Utc>>,
+}
+
+/// How many cached Things were retrieved from BGG within some age, e.g. "1 day" (and not any more recently)
+#[derive(sqlx::FromRow, Debug, Clone, Serialize, PartialEq)]
+pub(crate) struct AgeBucket {
+    pub within: String,
+    pub things: i64,
+}
+
+pub(crate) async fn cache_counts<'a, DB>(db: DB) -> Result<CacheCounts, Error>
+where DB: Executor<'a, Database = Postgres> + 'a {
+    let _timer = QueryTimer::start("cache_counts");
+    Ok(query_as!(
+        CacheCounts,
+        r#"select
+  (select count(*) from bgg_thing) as "things!",
+  (select count(*) from bgg_category) as "categories!",
+  (select count(*) from bgg_family) as "families!",
+  (select count(*) from bgg_designer) as "designers!",
+  (select count(*) from bgg_publisher) as "publishers!",
+  (select count(*) from bgg_mechanic) as "mechanics!",
+  (select count(*) from bgg_tag) as "tags!",
+  (select count(*) from bgg_missing_thing) as "missing_things!",
+  (select min(retreived_at) from bgg_thing) as "oldest_retrieved",
+  (select max(retreived_at) from bgg_thing) as "newest_retrieved""#
+    ).fetch_one(db).await?)
+}
+
+/// The cached Things by how long ago they were retrieved, newest first; empty buckets are left out
+pub(crate) async fn cache_ages<'a, DB>(db: DB) -> Result<Vec<AgeBucket>, Error>
+where DB: Executor<'a, Database = Postgres> + 'a {
+    let _timer = QueryTimer::start("cache_ages");
+    Ok(query_as!(
+        AgeBucket,
+        r#"select B.within as "within!", count(*) as "things!"
+  from (select case
+      when retreived_at > now() - interval '1 day' then 1
+      when retreived_at > now() - interval '7 days' then 2
+      when retreived_at > now() - interval '30 days' then 3
+      when retreived_at > now() - interval '90 days' then 4
+      when retreived_at > now() - interval '1 year' then 5
+      else 6 end as ord
+    from bgg_thing) as T
+  join (values (1, '1 day'), (2, '1 week'), (3, '30 days'), (4, '90 days'), (5, '1 year'), (6, 'older')) as B(ord, within) using (ord)
+  group by ord, B.within
+  order by ord"#
+    ).fetch_all(db).await?)
+}
+
+/// How long we take BGG's word that it has no Thing for an id
+const MISSING_TTL_DAYS: i32 = 7;
+
+/// Remembers that BGG had no Thing for these ids, so that we can stop asking for a while.
+pub(crate) async fn record_missing<'a, DB>(db: DB, bgg_ids: &[String]) -> Result<(), Error>
+where DB: Executor<'a, Database = Postgres> + 'a {
+    let _timer = QueryTimer::start("record_missing");
+    query!(
+        r#"insert into bgg_missing_thing ("bgg_id")
+  select bgg_id from unnest($1::text[]) as a(bgg_id)
+  on conflict (bgg_id) do update set checked_at = now()"#,
+        bgg_ids
+    ).execute(db).await?;
+    Ok(())
+}
+
+/// Those of these ids that BGG has recently told us it has no Thing for
+pub(crate) async fn known_missing<'a, DB>(db: DB, bgg_ids: &[String]) -> Result<Vec<String>, Error>
+where DB: Executor<'a, Database = Postgres> + 'a {
+    let _timer = QueryTimer::start("known_missing");
+    Ok(query_scalar!(
+        r#"select bgg_id from bgg_missing_thing
+  where bgg_id = any($1) and checked_at > now() - make_interval(days => $2)"#,
+        bgg_ids, MISSING_TTL_DAYS
+    ).fetch_all(db).await?)
+}
+
+/// Those of these revocation ids that are in our own revocation list
+pub(crate) async fn revoked<'a, DB>(db: DB, revocation_ids: &[String]) -> Result<Vec<String>, Error>
+where DB: Executor<'a, Database = Postgres> + 'a {
+    let _timer = QueryTimer::start("revoked");
+    Ok(query_scalar!(
+        r#"select revocation_id from revoked_biscuit where revocation_id = any($1)"#,
+        revocation_ids
+    ).fetch_all(db).await?)
+}
+
+/// A locally issued API key; only its hash is kept
+#[derive(sqlx::FromRow, Debug, Clone, PartialEq)]
+pub(crate) struct ApiKeyRecord {
+    pub name: String,
+    pub key_hash: String,
+    pub tier: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// All the API keys, revoked or not, by name
+pub(crate) async fn api_keys<'a, DB>(db: DB) -> Result<Vec<ApiKeyRecord>, Error>
+where DB: Executor<'a, Database = Postgres> + 'a {
+    let _timer = QueryTimer::start("api_keys");
+    Ok(query_as!(
+        ApiKeyRecord,
+        r#"select name, key_hash, tier, scopes, created_at, revoked_at from api_key order by name"#
+    ).fetch_all(db).await?)
+}
+
+pub(crate) async fn create_api_key<'a, DB>(db: DB, name: &str, key_hash: &str, tier: &str, scopes: &[String]) -> Result<(), Error>
+where DB: Executor<'a, Database = Postgres> + 'a {
+    let _timer = QueryTimer::start("create_api_key");
+    query!(
+        r#"insert into api_key ("name", "key_hash", "tier", "scopes") values ($1, $2, $3, $4)"#,
+        name, key_hash, tier, scopes
+    ).execute(db).await?;
+    Ok(())
+}
+
+/// Revokes the named API key; returns whether there was an unrevoked one to revoke
+pub(crate) async fn revoke_api_key<'a, DB>(db: DB, name: &str) -> Result<bool, Error>
+where DB: Executor<'a, Database = Postgres> + 'a {
+    let _timer = QueryTimer::start("revoke_api_key");
+    Ok(query!(
+        r#"update api_key set revoked_at = now() where name = $1 and revoked_at is null"#,
+        name
+    ).execute(db).await?.rows_affected() > 0)
+}
+
+/// What an authenticated request was, who made it, and what it cost us
+#[derive(sqlx::FromRow, Debug, Clone, Default, PartialEq)]
+pub(crate) struct AuditEntry {
+    pub authority: Option<String>,
+    pub user_name: Option<String>,
+    pub route: String,
+    pub query: Option<String>,
+    pub status: i32,
+    pub cache_hits: i32,
+    pub cache_misses: i32,
+    pub upstream_calls: i32,
+}
+
+pub(crate) async fn record_audit<'a, DB>(db: DB, entry: &AuditEntry) -> Result<(), Error>
+where DB: Executor<'a, Database = Postgres> + 'a {
+    let _timer = QueryTimer::start("record_audit");
+    query!(
+        r#"insert into audit_log ("authority", "user_name", "route", "query", "status", "cache_hits", "cache_misses", "upstream_calls")
+  values ($1, $2, $3, $4, $5, $6, $7, $8)"#,
+        entry.authority, entry.user_name, entry.route, entry.query, entry.status,
+        entry.cache_hits, entry.cache_misses, entry.upstream_calls
+    ).execute(db).await?;
+    Ok(())
+}
+
+/// Deletes audit entries older than `days`, returning how many there were
+pub(crate) async fn prune_audit<'a, DB>(db: DB, days: i32) -> Result<u64, Error>
+where DB: Executor<'a, Database = Postgres> + 'a {
+    let _timer = QueryTimer::start("prune_audit");
+    Ok(query!(
+        r#"delete from audit_log where at < now() - make_interval(days => $1)"#,
+        days
+    ).execute(db).await?.rows_affected())
+}
+
+/// Today's calls to BGG on one user's behalf, for one endpoint
+#[derive(sqlx::FromRow, Debug, Clone, Serialize, PartialEq)]
+pub(crate) struct UpstreamUsage {
+    pub endpoint: String,
+    pub calls: i32,
+}
+
+/// Counts a call to BGG against the user it was made for.
+pub(crate) async fn record_upstream_call<'a, DB>(db: DB, authority: &str, user: &str, endpoint: &str) -> Result<(), Error>
+where DB: Executor<'a, Database = Postgres> + 'a {
+    let _timer = QueryTimer::start("record_upstream_call");
+    query!(
+        r#"insert into upstream_usage ("authority", "user_name", "endpoint", "calls")
+  values ($1, $2, $3, 1)
+  on conflict (authority, user_name, day, endpoint) do update set calls = upstream_usage.calls + 1"#,
+        authority, user, endpoint
+    ).execute(db).await?;
+    Ok(())
+}
+
+/// The calls to BGG made today on a user's behalf, by endpoint
+pub(crate) async fn upstream_usage<'a, DB>(db: DB, authority: &str, user: &str) -> Result<Vec<UpstreamUsage>, Error>
+where DB: Executor<'a, Database = Postgres> + 'a {
+    let _timer = QueryTimer::start("upstream_usage");
+    Ok(query_as!(
+        UpstreamUsage,
+        r#"select endpoint, calls from upstream_usage
+  where authority = $1 and user_name = $2 and day = current_date
+  order by endpoint"#,
+        authority, user
+    ).fetch_all(db).await?)
+}
+
+/// The user's own daily quota of calls to BGG, if they have one
+pub(crate) async fn upstream_quota<'a, DB>(db: DB, authority: &str, user: &str) -> Result<Option<i32>, Error>
+where DB: Executor<'a, Database = Postgres> + 'a {
+    let _timer = QueryTimer::start("upstream_quota");
+    Ok(query_scalar!(
+        r#"select daily_calls from upstream_quota where authority = $1 and user_name = $2"#,
+        authority, user
+    ).fetch_optional(db).await?)
+}
+
+impl BggThing<ThingId> {
+    /// All the cached Things that link to an entity, by name
+    pub async fn get_linked_to<'a, L, DB>(db: DB, link: &L) -> Result<Vec<Self>, Error>
+    where L: LinkEntity, DB: Executor<'a, Database = Postgres> + Copy + 'a {
+        let _timer = QueryTimer::start("get_linked_to");
+        let sql = format!(
+            "select T.bgg_id from bgg_thing T join {} J on J.thing_id = T.id where J.{} = $1 order by T.name, T.id",
+            L::JOIN_TABLE, L::JOIN_COLUMN
+        );
+        let bgg_ids: Vec<String> = query_scalar(&sql).bind(link.id()).fetch_all(db).await?;
+
+        let mut things = Self::get_for_bgg_ids(db, bgg_ids.clone()).await?;
+        things.sort_by_key(|thing| bgg_ids.iter().position(|id| *id == thing.data.bgg_id));
+        Ok(things)
+    }
+}
+
+/// For tests that need a Postgres to talk to: point DATABASE_URL at one whose user can create schemas.
+/// Each test migrates a fresh schema of its own, and drops it again if it passes.
+/// Tests using one live in a `db` module, wherever they are, so that `cargo test -- --skip db::` skips them all.
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::str::FromStr;
+
+    use sqlx::{postgres::{PgConnectOptions, PgPoolOptions}, query, PgPool};
+
+    pub(crate) struct TestSchema {
+        name: String,
+        pub pool: PgPool,
+    }
+
+    impl TestSchema {
+        pub async fn new() -> Self {
+            let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set for db tests");
+            let name = format!("test_{:08x}", rand::random::<u32>());
+
+            let admin = PgPool::connect(&url).await.expect("connect to test database");
+            query(&format!("create schema {name}")).execute(&admin).await.expect("create test schema");
+            admin.close().await;
+
+            let options = PgConnectOptions::from_str(&url).expect("parse DATABASE_URL")
+                .options([("search_path", name.as_str())]);
+            let pool = PgPoolOptions::new().connect_with(options).await.expect("connect to test schema");
+            sqlx::migrate!().run(&pool).await.expect("migrate test schema");
+
+            Self{name, pool}
+        }
+
+        pub async fn drop(self) {
+            query(&format!("drop schema {} cascade", self.name)).execute(&self.pool).await.expect("drop test schema");
+            self.pool.close().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::*;
+    use super::testing::TestSchema;
+    use crate::bgg_api::parse_things;
+
+    fn fixture(xml: &[u8]) -> Vec<BggThing<NoId>> {
+        parse_things(xml).expect("fixture parses")
+    }
+
+    // The database doesn't keep altnames or links in any particular order, nor duplicates of them
+    fn normalized(mut data: ThingData, mut links: ThingLinks) -> (ThingData, ThingLinks) {
+        data.altnames.sort();
+        data.altnames.dedup();
+        for list in [&mut links.categories, &mut links.families, &mut links.designers, &mut links.publishers, &mut links.mechanics] {
+            list.sort_by(|l, r| l.bgg_id.cmp(&r.bgg_id));
+            list.dedup();
+        }
+        links.tags.sort_by(|l, r| (&l.link_type, &l.bgg_id).cmp(&(&r.link_type, &r.bgg_id)));
+        links.tags.dedup();
+        (data, links)
+    }
+
+    async fn assert_round_trip(db: &PgPool, things: &[BggThing<NoId>]) {
+        let ids = things.iter().map(|thing| thing.data.bgg_id.clone()).collect::<Vec<_>>();
+        let stored = BggThing::get_for_bgg_ids(db, ids).await.expect("load things");
+        assert_eq!(stored.len(), things.len());
+
+        for thing in things {
+            let found = stored.iter().find(|s| s.data.bgg_id == thing.data.bgg_id).expect("thing was stored");
+            let (expected_data, expected_links) = normalized(thing.data.clone(), thing.links.clone());
+            let (data, links) = normalized(found.data.clone(), found.links.clone());
+            assert_eq!(data, expected_data);
+            assert_eq!(links.categories, expected_links.categories);
+            assert_eq!(links.families, expected_links.families);
+            assert_eq!(links.designers, expected_links.designers);
+            assert_eq!(links.publishers, expected_links.publishers);
+            assert_eq!(links.mechanics, expected_links.mechanics);
+            assert_eq!(links.tags, expected_links.tags);
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let schema = TestSchema::new().await;
+        let things = [
+            fixture(include_bytes!("../testdata/thing-chess.xml")),
+            fixture(include_bytes!("../testdata/thing-duhr.xml")),
+        ].concat();
+
+        for thing in &things {
+            thing.add_new(&schema.pool).await.expect("store thing");
+        }
+        assert_round_trip(&schema.pool, &things).await;
+
+        schema.drop().await;
+    }
+
+    #[tokio::test]
+    async fn unknown_ids_are_left_out() {
+        let schema = TestSchema::new().await;
+        let things = fixture(include_bytes!("../testdata/thing-duhr.xml"));
+        things[0].add_new(&schema.pool).await.expect("store thing");
+
+        let stored = BggThing::get_for_bgg_ids(&schema.pool, vec!["1".to_string(), "246508".to_string()]).await.expect("load things");
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].data.bgg_id, "246508");
+
+        let stored = BggThing::get_for_bgg_ids(&schema.pool, vec![]).await.expect("load no things");
+        assert!(stored.is_empty());
+
+        schema.drop().await;
+    }
+
+    #[tokio::test]
+    async fn shared_links() {
+        let schema = TestSchema::new().await;
+        let mut things = fixture(include_bytes!("../testdata/thing-duhr.xml"));
+        let mut second = things[0].clone();
+        second.data.bgg_id = "1246508".to_string();
+        second.data.name = Some("Dȗhr: The Greater Houses".to_string());
+        second.links.mechanics.truncate(1);
+        things.push(second);
+
+        for thing in &things {
+            thing.add_new(&schema.pool).await.expect("store thing");
+        }
+        assert_round_trip(&schema.pool, &things).await;
+
+        schema.drop().await;
+    }
+
+    #[tokio::test]
+    async fn storing_again_refreshes() {
+        let schema = TestSchema::new().await;
+        let mut things = fixture(include_bytes!("../testdata/thing-duhr.xml"));
+        things[0].add_new(&schema.pool).await.expect("store thing");
+        let first = BggThing::get_for_bgg_ids(&schema.pool, vec!["246508".to_string()]).await.expect("load thing").remove(0);
+
+        let thing = &mut things[0];
+        thing.data.max_players = Some(8);
+        thing.data.altnames = vec!["Duhr".to_string()];
+        thing.links.categories.pop();
+        thing.links.designers.push(LinkData{bgg_id: "1".to_string(), name: "Someone Else".to_string()});
+        thing.add_new(&schema.pool).await.expect("store thing again");
+
+        assert_round_trip(&schema.pool, &things).await;
+
+        let second = BggThing::get_for_bgg_ids(&schema.pool, vec!["246508".to_string()]).await.expect("load thing").remove(0);
+        assert_eq!(second.id, first.id);
+        assert_eq!(second.created_at, first.created_at);
+        assert!(second.updated_at > first.updated_at);
+        assert!(second.retreived_at > first.retreived_at);
+
+        let count: i64 = query_scalar("select count(*) from bgg_thing").fetch_one(&schema.pool).await.expect("count things");
+        assert_eq!(count, 1);
+
+        schema.drop().await;
+    }
+
+    #[tokio::test]
+    async fn missing_things() {
+        let schema = TestSchema::new().await;
+        let ids = vec!["246508".to_string(), "0".to_string()];
+        record_missing(&schema.pool, &ids).await.expect("record missing");
+        record_missing(&schema.pool, &ids[1..]).await.expect("record missing again");
+
+        let mut missing = known_missing(&schema.pool, &[ids[0].clone(), ids[1].clone(), "1".to_string()]).await.expect("check missing");
+        missing.sort();
+        assert_eq!(missing, vec!["0".to_string(), "246508".to_string()]);
+
+        let things = fixture(include_bytes!("../testdata/thing-duhr.xml"));
+        things[0].add_new(&schema.pool).await.expect("store thing");
+        let missing = known_missing(&schema.pool, &ids).await.expect("check missing");
+        assert_eq!(missing, vec!["0".to_string()]);
+
+        query("update bgg_missing_thing set checked_at = now() - interval '30 days'").execute(&schema.pool).await.expect("age missing");
+        assert!(known_missing(&schema.pool, &ids).await.expect("check missing").is_empty());
+
+        schema.drop().await;
+    }
+
+    #[tokio::test]
+    async fn upstream_usage_by_user() {
+        let schema = TestSchema::new().await;
+        for endpoint in ["thing", "search", "thing"] {
+            record_upstream_call(&schema.pool, "example.com", "alice", endpoint).await.expect("record call");
+        }
+        record_upstream_call(&schema.pool, "example.com", "bob", "thing").await.expect("record call");
+        record_upstream_call(&schema.pool, "example.org", "alice", "thing").await.expect("record call");
+
+        let usage = upstream_usage(&schema.pool, "example.com", "alice").await.expect("usage");
+        assert_eq!(usage, vec![
+            UpstreamUsage{endpoint: "search".to_string(), calls: 1},
+            UpstreamUsage{endpoint: "thing".to_string(), calls: 2},
+        ]);
+
+        query("update upstream_usage set day = day - 1").execute(&schema.pool).await.expect("age usage");
+        assert!(upstream_usage(&schema.pool, "example.com", "alice").await.expect("usage").is_empty());
+
+        assert_eq!(upstream_quota(&schema.pool, "example.com", "alice").await.expect("quota"), None);
+        query("insert into upstream_quota values ('example.com', 'alice', 50)").execute(&schema.pool).await.expect("set quota");
+        assert_eq!(upstream_quota(&schema.pool, "example.com", "alice").await.expect("quota"), Some(50));
+        assert_eq!(upstream_quota(&schema.pool, "example.org", "alice").await.expect("quota"), None);
+
+        schema.drop().await;
+    }
+
+    #[tokio::test]
+    async fn revoked_biscuits() {
+        let schema = TestSchema::new().await;
+        query("insert into revoked_biscuit (revocation_id, reason) values ('bGVha2Vk', 'leaked')")
+            .execute(&schema.pool).await.expect("revoke biscuit");
+
+        let ids = vec!["b2s=".to_string(), "bGVha2Vk".to_string()];
+        assert_eq!(revoked(&schema.pool, &ids).await.expect("check revoked"), vec!["bGVha2Vk".to_string()]);
+        assert!(revoked(&schema.pool, &ids[..1]).await.expect("check revoked").is_empty());
+
+        schema.drop().await;
+    }
+
+    #[tokio::test]
+    async fn purging_and_stats() {
+        let schema = TestSchema::new().await;
+        let mut things = fixture(include_bytes!("../testdata/thing-duhr.xml"));
+        things[0].links.tags.push(TagData{link_type: "boardgameaccessory".to_string(), bgg_id: "171".to_string(), name: "Chess".to_string()});
+        things[0].add_new(&schema.pool).await.expect("store thing");
+        query("update bgg_thing set retreived_at = now() - interval '10 days'").execute(&schema.pool).await.expect("age thing");
+
+        let counts = cache_counts(&schema.pool).await.expect("counts");
+        assert_eq!(counts.things, 1);
+        assert_eq!(counts.designers, things[0].links.designers.len() as i64);
+        assert_eq!(cache_ages(&schema.pool).await.expect("ages"), vec![AgeBucket{within: "30 days".to_string(), things: 1}]);
+
+        let designer = things[0].links.designers[0].bgg_id.clone();
+        assert!(purge_link::<BggDesigner<DesignerId>, _>(&schema.pool, &designer).await.expect("purge designer"));
+        assert!(!purge_link::<BggDesigner<DesignerId>, _>(&schema.pool, &designer).await.expect("purge designer again"));
+        let reloaded = BggThing::get_for_bgg_ids(&schema.pool, vec!["246508".to_string()]).await.expect("load thing").remove(0);
+        assert!(!reloaded.links.designers.iter().any(|link| link.bgg_id == designer));
+
+        assert!(!purge_tag(&schema.pool, "rpggenre", "171").await.expect("purge tag of another type"));
+        assert!(purge_tag(&schema.pool, "boardgameaccessory", "171").await.expect("purge tag"));
+        let reloaded = BggThing::get_for_bgg_ids(&schema.pool, vec!["246508".to_string()]).await.expect("load thing").remove(0);
+        assert!(reloaded.links.tags.is_empty());
+
+        let purged = purge_things(&schema.pool, &["246508".to_string(), "0".to_string()]).await.expect("purge things");
+        assert_eq!(purged, vec!["246508".to_string()]);
+        assert_eq!(cache_counts(&schema.pool).await.expect("counts").things, 0);
+        assert!(cache_ages(&schema.pool).await.expect("ages").is_empty());
+
+        schema.drop().await;
+    }
+
+    #[tokio::test]
+    async fn audit_retention() {
+        let schema = TestSchema::new().await;
+        let entry = AuditEntry{
+            authority: Some("example.com".to_string()),
+            user_name: Some("alice".to_string()),
+            route: "/api/search".to_string(),
+            query: Some("query=chess".to_string()),
+            status: 200,
+            cache_hits: 3,
+            cache_misses: 17,
+            upstream_calls: 2,
+        };
+        record_audit(&schema.pool, &entry).await.expect("record entry");
+        record_audit(&schema.pool, &AuditEntry{route: "/api/thing".to_string(), status: 401, ..Default::default()}).await.expect("record entry");
+
+        let entries: Vec<AuditEntry> = query_as("select authority, user_name, route, query, status, cache_hits, cache_misses, upstream_calls from audit_log order by id")
+            .fetch_all(&schema.pool).await.expect("load entries");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], entry);
+
+        query("update audit_log set at = now() - interval '100 days' where status = 401").execute(&schema.pool).await.expect("age entry");
+        assert_eq!(prune_audit(&schema.pool, 90).await.expect("prune"), 1);
+        assert_eq!(prune_audit(&schema.pool, 90).await.expect("prune again"), 0);
+
+        schema.drop().await;
+    }
+
+    #[tokio::test]
+    async fn batches_of_ids() {
+        let schema = TestSchema::new().await;
+        let count = BggThing::MAX_IDS * 2 + 500;
+        query("insert into bgg_thing (bgg_id, kind) select n::text, 'boardgame' from generate_series(1, $1) as n")
+            .bind(count as i32)
+            .execute(&schema.pool).await.expect("insert things");
+
+        let mut ids = (1..=count).map(|n| n.to_string()).collect::<Vec<_>>();
+        ids.push("0".to_string());
+        let stored = BggThing::get_for_bgg_ids(&schema.pool, ids).await.expect("load things");
+        assert_eq!(stored.len(), count);
+
+        let mut found = stored.iter().map(|thing| thing.data.bgg_id.parse::<usize>().unwrap()).collect::<Vec<_>>();
+        found.sort();
+        assert!(found.into_iter().eq(1..=count));
+
+        schema.drop().await;
+    }
+}
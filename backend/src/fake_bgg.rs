@@ -0,0 +1,203 @@
+//! A stand-in for the BGG XML API 2, for working offline and for integration tests.
+//!
+//! It serves the XML files in a data directory (by default, backend/testdata):
+//! search-*.xml answer /search, thing-*.xml are split into their items to answer /thing,
+//! and family-*.xml likewise answer /family.
+//! It can also be told to misbehave every so often, the way BGG does.
+//!
+//! Run the backend with BGG_API_URL=http://127.0.0.1:3002/xmlapi2 to use it.
+//! The `fake-bgg` binary serves it; the backend's own tests serve it themselves.
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicUsize, Ordering}, Arc},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use clap::Parser;
+use quick_xml::{events::Event, Reader};
+use tokio::net::TcpListener;
+use tracing::debug;
+
+#[derive(Parser)]
+pub(crate) struct Config {
+    #[arg(long, env = "FAKE_BGG_ADDR", default_value = "127.0.0.1:3002")]
+    local_addr: String,
+
+    /// Directory of BGG XML responses to serve
+    #[arg(long, env = "FAKE_BGG_DATA", default_value = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata"))]
+    data_dir: PathBuf,
+
+    /// Answer every Nth request with 429 Too Many Requests (0 to never)
+    #[arg(long, default_value = "0")]
+    rate_limit_every: usize,
+
+    /// Answer every Nth request with 202 Accepted and a "try again later" message (0 to never)
+    #[arg(long, default_value = "0")]
+    accepted_every: usize,
+
+    /// Answer every Nth request with 503 Service Unavailable (0 to never)
+    #[arg(long, default_value = "0")]
+    error_every: usize,
+}
+
+const TERMS: &str = "https://boardgamegeek.com/xmlapi/termsofuse";
+
+struct Fixtures {
+    searches: Vec<String>,
+    things: HashMap<String, String>,
+    families: HashMap<String, String>,
+}
+
+#[derive(Clone)]
+struct FakeState {
+    fixtures: Arc<Fixtures>,
+    requests: Arc<AtomicUsize>,
+    rate_limit_every: usize,
+    accepted_every: usize,
+    error_every: usize,
+}
+
+/// Loads the fixtures and binds the listener, for the binary or for a test to serve
+pub(crate) async fn bind(config: &Config) -> Result<(TcpListener, Router), Box<dyn Error>> {
+    let fixtures = load_fixtures(&config.data_dir)?;
+    debug!("loaded {} searches, {} things, {} families",
+        fixtures.searches.len(), fixtures.things.len(), fixtures.families.len());
+
+    let state = FakeState{
+        fixtures: Arc::new(fixtures),
+        requests: Arc::new(AtomicUsize::new(0)),
+        rate_limit_every: config.rate_limit_every,
+        accepted_every: config.accepted_every,
+        error_every: config.error_every,
+    };
+
+    let app = Router::new()
+        .route("/xmlapi2/search", get(search))
+        .route("/xmlapi2/thing", get(thing))
+        .route("/xmlapi2/family", get(family))
+        .with_state(state);
+
+    Ok((TcpListener::bind(&config.local_addr).await?, app))
+}
+
+fn load_fixtures(dir: &Path) -> Result<Fixtures, Box<dyn Error>> {
+    let mut fixtures = Fixtures{searches: vec![], things: HashMap::new(), families: HashMap::new()};
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string();
+        if !name.ends_with(".xml") {
+            continue
+        }
+        let text = std::fs::read_to_string(&path)?;
+        if name.starts_with("search-") {
+            fixtures.searches.push(text);
+        } else if name.starts_with("thing-") {
+            fixtures.things.extend(split_items(&text)?);
+        } else if name.starts_with("family-") {
+            fixtures.families.extend(split_items(&text)?);
+        }
+    }
+    Ok(fixtures)
+}
+
+/// Splits an <items> document into its top-level <item> elements, keyed by id
+fn split_items(text: &str) -> Result<Vec<(String, String)>, quick_xml::Error> {
+    let mut reader = Reader::from_str(text);
+    let mut items = vec![];
+    let mut depth = 0;
+    loop {
+        let start = reader.buffer_position() as usize;
+        match reader.read_event()? {
+            Event::Eof => break,
+            Event::Start(tag) if depth == 1 && tag.local_name().as_ref() == b"item" => {
+                let id = tag.try_get_attribute("id")?
+                    .map(|a| String::from_utf8_lossy(&a.value).to_string())
+                    .unwrap_or_default();
+                reader.read_to_end(tag.to_end().name())?;
+                let end = reader.buffer_position() as usize;
+                items.push((id, text[start..end].to_string()));
+            }
+            Event::Start(_) => depth += 1,
+            Event::End(_) => depth -= 1,
+            _ => ()
+        }
+    }
+    Ok(items)
+}
+
+impl FakeState {
+    /// Counts the request, and decides whether this is one to misbehave on
+    fn misbehave(&self) -> Option<Response> {
+        let count = self.requests.fetch_add(1, Ordering::SeqCst) + 1;
+        let every = |n: usize| n > 0 && count.is_multiple_of(n);
+        if every(self.rate_limit_every) {
+            debug!("request {count}: simulating rate limit");
+            Some((StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded.").into_response())
+        } else if every(self.error_every) {
+            debug!("request {count}: simulating server error");
+            Some((StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable").into_response())
+        } else if every(self.accepted_every) {
+            debug!("request {count}: simulating queued request");
+            Some(xml(StatusCode::ACCEPTED, "<message>Your request has been accepted and will be processed.  Please try again later for access.</message>".to_string()))
+        } else {
+            None
+        }
+    }
+}
+
+fn xml(status: StatusCode, body: String) -> Response {
+    (status, [(header::CONTENT_TYPE, "text/xml; charset=utf-8")], body).into_response()
+}
+
+fn items_document(items: Vec<&String>) -> String {
+    let mut doc = format!(r#"<?xml version="1.0" encoding="utf-8"?><items termsofuse="{TERMS}">"#);
+    for item in items {
+        doc.push_str(item);
+    }
+    doc.push_str("</items>");
+    doc
+}
+
+fn requested_ids(params: &HashMap<String, String>) -> Vec<String> {
+    params.get("id")
+        .map(|ids| ids.split(',').map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+async fn search(State(state): State<FakeState>, Query(params): Query<HashMap<String, String>>) -> Response {
+    if let Some(rz) = state.misbehave() {
+        return rz
+    }
+    debug!("search: {:?}", params.get("query"));
+    match state.fixtures.searches.first() {
+        Some(search) => xml(StatusCode::OK, search.clone()),
+        None => xml(StatusCode::OK, format!(r#"<?xml version="1.0" encoding="utf-8"?><items total="0" termsofuse="{TERMS}"></items>"#)),
+    }
+}
+
+async fn thing(State(state): State<FakeState>, Query(params): Query<HashMap<String, String>>) -> Response {
+    if let Some(rz) = state.misbehave() {
+        return rz
+    }
+    let ids = requested_ids(&params);
+    debug!("thing: {ids:?}");
+    // Like BGG, ids we don't know about are simply left out
+    xml(StatusCode::OK, items_document(ids.iter().filter_map(|id| state.fixtures.things.get(id)).collect()))
+}
+
+async fn family(State(state): State<FakeState>, Query(params): Query<HashMap<String, String>>) -> Response {
+    if let Some(rz) = state.misbehave() {
+        return rz
+    }
+    let ids = requested_ids(&params);
+    debug!("family: {ids:?}");
+    xml(StatusCode::OK, items_document(ids.iter().filter_map(|id| state.fixtures.families.get(id)).collect()))
+}
@@ -0,0 +1,340 @@
+//! The upstream authorities' Biscuit Well-known Key Sets, kept fresh.
+//!
+//! mattak fetches a KeyMap once; authorities rotate their keys whenever they like.
+//! KeySets holds the last KeyMap fetched successfully, refetches it periodically in the background,
+//! and early when a biscuit names a root key id we haven't seen yet.
+//! A failed fetch leaves the last good KeyMap in place.
+//!
+//! Biscuits minted for API keys are checked against the API keys' root key instead.
+use std::{sync::{Arc, RwLock}, time::Duration};
+
+use axum::{
+    body::Body, extract::{Request, State}, http::{HeaderMap, Uri}, middleware::Next, response::Response
+};
+use biscuit_auth::{error, PublicKey, RootKeyProvider, UnverifiedBiscuit};
+use mattak::biscuits::{
+    self, keysets::{AuthorityMap, KeyMap, WebKeyProvider}, middleware::setup::{GetPublic, GetPublicSource}
+};
+use reqwest::Client;
+use tokio::{sync::Mutex, time::{sleep, Instant}};
+use tracing::{debug, info, warn};
+
+use crate::{api_keys::{ApiKeys, API_KEY_HEADER}, auth::AUTH_HEADER};
+
+/// How soon after one fetch a biscuit with an unknown key id can cause another
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(30);
+/// The first wait before retrying a failed background fetch; it doubles up to the refresh interval
+const FIRST_RETRY: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+pub(crate) struct KeySets {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    authorities: AuthorityMap,
+    client: Client,
+    current: RwLock<KeyMap>,
+    /// When the last fetch was attempted, held while fetching so that fetches don't pile up
+    last_fetch: Mutex<Instant>,
+    api_keys: ApiKeys,
+}
+
+impl KeySets {
+    /// Fetches every authority's keys; unlike later refreshes, this has to succeed.
+    pub async fn fetch(authorities: AuthorityMap, client: Client, api_keys: ApiKeys) -> Result<Self, biscuits::Error> {
+        let current = authorities.fetch_keys(client.clone()).await?;
+        debug!("{current:?}");
+        Ok(Self{inner: Arc::new(Inner{
+            authorities,
+            client,
+            current: RwLock::new(current),
+            last_fetch: Mutex::new(Instant::now()),
+            api_keys,
+        })})
+    }
+
+    pub fn api_keys(&self) -> &ApiKeys {
+        &self.inner.api_keys
+    }
+
+    /// Refetches the keys, unless that was tried less than `min_interval` ago.
+    /// On failure the keys we had are kept.
+    pub async fn refresh(&self, min_interval: Duration) -> Result<(), biscuits::Error> {
+        let mut last_fetch = self.inner.last_fetch.lock().await;
+        if last_fetch.elapsed() < min_interval {
+            debug!("key sets fetched {:?} ago, not refetching", last_fetch.elapsed());
+            return Ok(());
+        }
+        *last_fetch = Instant::now();
+
+        let keys = self.inner.authorities.fetch_keys(self.inner.client.clone()).await?;
+        debug!("refreshed key sets: {keys:?}");
+        *self.inner.current.write().expect("key sets lock poisoned") = keys;
+        Ok(())
+    }
+
+    /// Refreshes the keys every `every`, backing off from failed attempts.
+    pub fn spawn_refresher(&self, every: Duration) {
+        let keys = self.clone();
+        tokio::spawn(async move {
+            let mut backoff = Backoff::new(every);
+            let mut wait = every;
+            loop {
+                sleep(wait).await;
+                wait = match keys.refresh(Duration::ZERO).await {
+                    Ok(()) => backoff.next(true),
+                    Err(err) => {
+                        let wait = backoff.next(false);
+                        warn!("couldn't refresh key sets, keeping the last good ones; retrying in {wait:?}: {err:?}");
+                        wait
+                    }
+                };
+            }
+        });
+    }
+
+    /// Whether we have the root key the request's biscuit was signed with.
+    /// Requests without a readable biscuit, or for an unknown authority, aren't our concern here.
+    fn knows_root_key(&self, headers: &HeaderMap, uri: &Uri) -> bool {
+        let Some(key_id) = headers.get(AUTH_HEADER)
+            .and_then(|token| UnverifiedBiscuit::from_base64(token.as_bytes()).ok())
+            .map(|token| token.root_key_id())
+        else {
+            return true
+        };
+
+        let mut probe = Request::new(Body::empty());
+        *probe.uri_mut() = uri.clone();
+        *probe.headers_mut() = headers.clone();
+        match self.get_public(&probe) {
+            Ok(provider) => provider.choose(key_id).is_ok(),
+            Err(_) => true,
+        }
+    }
+}
+
+/// How long to wait between background refreshes: `every` while they work,
+/// and after a failure, FIRST_RETRY, doubling with each further failure up to `every`.
+struct Backoff {
+    every: Duration,
+    retry: Duration,
+}
+
+impl Backoff {
+    fn new(every: Duration) -> Self {
+        Self{every, retry: FIRST_RETRY}
+    }
+
+    fn next(&mut self, refreshed: bool) -> Duration {
+        if refreshed {
+            self.retry = FIRST_RETRY;
+            return self.every;
+        }
+        let wait = self.retry.min(self.every);
+        self.retry = (self.retry * 2).min(self.every);
+        wait
+    }
+}
+
+impl GetPublicSource for KeySets {
+    type Public = KeySets;
+
+    fn make_get_public(&self) -> Self::Public {
+        self.clone()
+    }
+}
+
+/// The keys a request's biscuit might be signed with
+pub(crate) enum RootKeys {
+    Authority(WebKeyProvider),
+    ApiKey(PublicKey),
+}
+
+impl RootKeyProvider for RootKeys {
+    fn choose(&self, key_id: Option<u32>) -> Result<PublicKey, error::Format> {
+        match self {
+            RootKeys::Authority(keys) => keys.choose(key_id),
+            RootKeys::ApiKey(key) => Ok(*key),
+        }
+    }
+}
+
+impl GetPublic for KeySets {
+    type PublicKey = RootKeys;
+
+    fn get_public(&self, req: &Request) -> Result<Self::PublicKey, biscuits::Error> {
+        if req.headers().contains_key(API_KEY_HEADER) {
+            return Ok(RootKeys::ApiKey(self.inner.api_keys.root()));
+        }
+        self.inner.current.read().expect("key sets lock poisoned").get_public(req).map(RootKeys::Authority)
+    }
+}
+
+/// Middleware, to go ahead of the biscuits setup:
+/// if the request's biscuit names a root key we don't have, the authority may have rotated its keys,
+/// so refetch them before carrying on.
+pub(crate) async fn refetch_unknown(State(keys): State<KeySets>, req: Request, next: Next) -> Response {
+    if !keys.knows_root_key(req.headers(), req.uri()) {
+        info!("biscuit signed with an unknown root key, refetching key sets");
+        if let Err(err) = keys.refresh(MIN_REFETCH_INTERVAL).await {
+            warn!("couldn't refetch key sets: {err:?}");
+        }
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::http::{header::HOST, HeaderValue};
+    use biscuit_auth::{macros::biscuit, Biscuit, BiscuitWebKey, KeyPair};
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+    use tokio_rustls::{rustls::{pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer}, ServerConfig}, TlsAcceptor};
+
+    use super::*;
+
+    pub(crate) const HOST_NAME: &str = "bgg.example.com";
+
+    /// An authority serving its key set over HTTPS, as mattak insists, with a certificate for localhost
+    pub(crate) struct StubAuthority {
+        keyset: String,
+        keys: Arc<std::sync::Mutex<Option<Vec<PublicKey>>>>,
+        fetches: Arc<AtomicUsize>,
+    }
+
+    impl StubAuthority {
+        pub(crate) async fn start(keys: Vec<PublicKey>) -> Self {
+            let certs = CertificateDer::pem_slice_iter(include_bytes!("../testdata/authority/cert.pem"))
+                .collect::<Result<Vec<_>, _>>().expect("test certificate");
+            let key = PrivateKeyDer::from_pem_slice(include_bytes!("../testdata/authority/key.pem")).expect("test key");
+            let config = ServerConfig::builder().with_no_client_auth().with_single_cert(certs, key).expect("TLS config");
+            let acceptor = TlsAcceptor::from(Arc::new(config));
+            let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind stub authority");
+            let keyset = format!("localhost:{}", listener.local_addr().expect("local addr").port());
+
+            let authority = Self{keyset, keys: Arc::new(std::sync::Mutex::new(Some(keys))), fetches: Arc::default()};
+            let (keys, fetches) = (authority.keys.clone(), authority.fetches.clone());
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let (acceptor, keys, fetches) = (acceptor.clone(), keys.clone(), fetches.clone());
+                    tokio::spawn(async move {
+                        let Ok(mut stream) = acceptor.accept(stream).await else { return };
+                        let mut request = vec![];
+                        let mut buf = [0; 1024];
+                        while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+                            match stream.read(&mut buf).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => request.extend_from_slice(&buf[..n]),
+                            }
+                        }
+                        fetches.fetch_add(1, Ordering::SeqCst);
+                        let keys = keys.lock().expect("stub keys lock").clone();
+                        let (status, body) = match keys {
+                            Some(keys) => ("200 OK", serde_json::to_string(&keys.into_iter().enumerate()
+                                .map(|(key_id, public_key)| BiscuitWebKey{public_key, key_id: key_id as u32, issuer: None, expires_at: None})
+                                .collect::<Vec<_>>()).expect("serialize keys")),
+                            None => ("503 Service Unavailable", String::new()),
+                        };
+                        let response = format!("HTTP/1.1 {status}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}", body.len());
+                        let _ = stream.write_all(response.as_bytes()).await;
+                        let _ = stream.shutdown().await;
+                    });
+                }
+            });
+            authority
+        }
+
+        /// Serve these keys from now on, or fail with None
+        fn serve(&self, keys: Option<Vec<PublicKey>>) {
+            *self.keys.lock().expect("stub keys lock") = keys;
+        }
+
+        fn fetches(&self) -> usize {
+            self.fetches.load(Ordering::SeqCst)
+        }
+
+        pub(crate) async fn key_sets(&self) -> KeySets {
+            let client = Client::builder()
+                .add_root_certificate(reqwest::Certificate::from_pem(include_bytes!("../testdata/authority/ca.pem")).expect("test CA"))
+                .build().expect("client");
+            KeySets::fetch(AuthorityMap::from(vec![(HOST_NAME, self.keyset.as_str())]), client, ApiKeys::fixed(vec![])).await
+                .expect("fetch key sets")
+        }
+    }
+
+    /// Headers for a request to HOST_NAME with the biscuit
+    pub(crate) fn headers(token: &Biscuit) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(HOST, HeaderValue::from_static(HOST_NAME));
+        headers.insert(AUTH_HEADER, token.to_base64().expect("encode biscuit").parse().expect("header value"));
+        headers
+    }
+
+    fn uri() -> Uri {
+        Uri::from_static("/api/thing?id=171")
+    }
+
+    #[tokio::test]
+    async fn refreshing_is_throttled() {
+        let authority = StubAuthority::start(vec![KeyPair::new().public()]).await;
+        let keys = authority.key_sets().await;
+        assert_eq!(authority.fetches(), 1);
+
+        keys.refresh(Duration::from_secs(60)).await.expect("throttled refresh");
+        assert_eq!(authority.fetches(), 1);
+        keys.refresh(Duration::ZERO).await.expect("refresh");
+        assert_eq!(authority.fetches(), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_refreshes_keep_the_last_good_keys() {
+        let root = KeyPair::new();
+        let authority = StubAuthority::start(vec![root.public()]).await;
+        let keys = authority.key_sets().await;
+
+        authority.serve(None);
+        assert!(keys.refresh(Duration::ZERO).await.is_err());
+        assert_eq!(authority.fetches(), 2);
+
+        let token = biscuit!(r#"user("alice");"#).build(&root).expect("build biscuit");
+        let mut probe = Request::new(Body::empty());
+        *probe.headers_mut() = headers(&token);
+        let root_keys = keys.get_public(&probe).expect("keys for the authority");
+        assert_eq!(root_keys.choose(None).expect("root key"), root.public());
+    }
+
+    #[tokio::test]
+    async fn knowing_root_keys() {
+        let (old, new) = (KeyPair::new(), KeyPair::new());
+        let authority = StubAuthority::start(vec![old.public()]).await;
+        let keys = authority.key_sets().await;
+
+        let current = biscuit!(r#"user("alice");"#).build(&old).expect("build biscuit");
+        let rotated = biscuit!(r#"user("alice");"#).root_key_id(1).build(&new).expect("build biscuit");
+        assert!(keys.knows_root_key(&headers(&current), &uri()));
+        assert!(!keys.knows_root_key(&headers(&rotated), &uri()));
+
+        // Without a biscuit, or for another authority, it's not for us to say
+        assert!(keys.knows_root_key(&HeaderMap::new(), &uri()));
+        let mut elsewhere = headers(&rotated);
+        elsewhere.insert(HOST, HeaderValue::from_static("elsewhere.example.com"));
+        assert!(keys.knows_root_key(&elsewhere, &uri()));
+
+        authority.serve(Some(vec![old.public(), new.public()]));
+        keys.refresh(Duration::ZERO).await.expect("refresh");
+        assert!(keys.knows_root_key(&headers(&rotated), &uri()));
+    }
+
+    #[test]
+    fn backing_off_failed_refreshes() {
+        let mut backoff = Backoff::new(Duration::from_secs(60));
+        let waits = [false, false, false, false, false, false, true, false].map(|refreshed| backoff.next(refreshed).as_secs());
+        assert_eq!(waits, [5, 10, 20, 40, 60, 60, 60, 5]);
+
+        let mut impatient = Backoff::new(Duration::from_secs(2));
+        assert_eq!(impatient.next(false), Duration::from_secs(2));
+    }
+}
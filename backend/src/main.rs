@@ -1,13 +1,20 @@
-use std::{net::SocketAddr, num::ParseIntError, time::Duration};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
-use axum::{extract, response::IntoResponse, routing::get, Router};
+use axum::{extract, middleware, response::IntoResponse, routing::{delete, get, post, put}, Router};
 use clap::Parser;
 use mattak::{
-    biscuits::{self, keysets::{AuthorityMap, KeyMap}}, cachecontrol::CacheControlLayer, ratelimiting::{self, GovernorConfigBuilder, IpExtractor}
+    biscuits::{self, keysets::AuthorityMap}, cachecontrol::CacheControlLayer, ratelimiting::IpExtractor
 };
 use biscuit_auth::macros::authorizer;
 use reqwest::{header, Certificate, Client, Method, StatusCode};
-use resources::{api_doc, branding, search, thing};
+use api_keys::ApiKeys;
+use audit::Auditor;
+use bgg_api::{BggClient, Traffic};
+use keysets::KeySets;
+use quota::DailyQuota;
+use revocation::RevocationLists;
+use ratelimit::{RateLimits, UserOrIpExtractor};
+use resources::{admin, api_doc, branding, find, links, quota as quota_resource, search, search_events, thing};
 use sqlx::{postgres::{PgConnectOptions, PgPoolOptions}, Pool, Postgres};
 use tracing::debug;
 use tracing_subscriber::{EnvFilter, prelude::*};
@@ -17,12 +24,27 @@ use tower_http::{trace::TraceLayer, cors::CorsLayer};
 mod resources;
 mod db;
 mod bgg_api;
+mod auth;
+mod ratelimit;
+mod keysets;
+mod revocation;
+mod audit;
+mod api_keys;
+mod quota;
+mod telemetry;
+#[cfg(test)]
+mod fake_bgg;
 
 #[derive(Parser)]
+#[command(after_help = "Run `bggapi-backend api-key --help` to manage API keys.")]
 struct Config {
     #[arg(long, env = "LOCAL_ADDR", default_value = "127.0.0.1:3001")]
     local_addr: String,
 
+    /// Where to serve Prometheus metrics, at /metrics. Kept apart from the API so that it needn't be public.
+    #[arg(long, env = "METRICS_ADDR", default_value = "127.0.0.1:9464")]
+    metrics_addr: String,
+
     /// Canonical domain the site is served from. Will be used in messages sent via email
     #[arg(long, env = "CANON_DOMAIN")]
     canon_domain: String,
@@ -36,12 +58,60 @@ struct Config {
     #[arg(long, env = "BGG_API_TOKEN")]
     bgg_api_token: String,
 
+    /// Base URL of the BGG XML API 2. Point this at fake-bgg to work offline.
+    #[arg(long, env = "BGG_API_URL", default_value = "https://boardgamegeek.com/xmlapi2")]
+    bgg_api_url: String,
+
+    /// Record every response from BGG into this directory
+    #[arg(long, env = "BGG_RECORD_DIR", conflicts_with = "bgg_replay_dir")]
+    bgg_record_dir: Option<PathBuf>,
+
+    /// Answer requests for BGG from the responses recorded in this directory, instead of from BGG
+    #[arg(long, env = "BGG_REPLAY_DIR")]
+    bgg_replay_dir: Option<PathBuf>,
+
     #[arg(long, env = "BGG_SIMULTANEUS_REQUESTS", default_value = "10")]
     bgg_simultaneus_requests: usize,
 
+    /// How many calls to BGG each user's requests may cause per day.
+    /// Individual users can be given their own quota in the upstream_quota table.
+    #[arg(long, env = "UPSTREAM_DAILY_QUOTA", default_value = "2000")]
+    upstream_daily_quota: i32,
+
     #[arg(long, env = "AUTH_MAP")]
     auth_map: String,
 
+    /// Rate limit tiers: name=millis:burst, comma-separated, where each request takes millis to earn back.
+    /// There has to be a "default" tier; biscuits and API keys can name others with a tier fact.
+    #[arg(long, env = "RATE_LIMIT_TIERS", default_value = "default=200:600")]
+    rate_limit_tiers: String,
+
+    /// The rate limit tier for requests to each authority, when their biscuit doesn't name one:
+    /// host=tier pairs, like AUTH_MAP
+    #[arg(long, env = "RATE_LIMIT_AUTHORITY_TIERS", default_value = "")]
+    rate_limit_authority_tiers: String,
+
+    /// Where each authority publishes the revocation ids of biscuits it has revoked:
+    /// host=url pairs, like AUTH_MAP. Each list is a JSON array of ids.
+    #[arg(long, env = "REVOCATION_MAP", default_value = "")]
+    revocation_map: String,
+
+    /// How often to refetch the authorities' revocation lists, in seconds
+    #[arg(long, env = "REVOCATION_REFRESH_SECS", default_value = "300", value_parser = clap::value_parser!(u64).range(1..))]
+    revocation_refresh_secs: u64,
+
+    /// How many days to keep the audit log of authenticated requests for
+    #[arg(long, env = "AUDIT_RETENTION_DAYS", default_value = "90", value_parser = clap::value_parser!(i32).range(1..))]
+    audit_retention_days: i32,
+
+    /// How often to reload API keys from the database, in seconds
+    #[arg(long, env = "API_KEY_RELOAD_SECS", default_value = "60", value_parser = clap::value_parser!(u64).range(1..))]
+    api_key_reload_secs: u64,
+
+    /// How often to refetch the authorities' key sets, in seconds
+    #[arg(long, env = "KEYSET_REFRESH_SECS", default_value = "3600", value_parser = clap::value_parser!(u64).range(1..))]
+    keyset_refresh_secs: u64,
+
     #[arg(long, env = "CORS_ORIGINS")]
     cors_origins: String,
 
@@ -62,9 +132,10 @@ impl From<BggLimit> for usize {
 #[derive(extract::FromRef, Clone)]
 struct AppState {
     pool: Pool<Postgres>,
-    client: Client,
+    client: BggClient,
     bgg_limit: BggLimit,
-    key_map: KeyMap,
+    daily_quota: DailyQuota,
+    key_sets: KeySets,
 }
 
 
@@ -75,8 +146,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with(EnvFilter::from_default_env())
         .init();
 
+    if std::env::args().nth(1).as_deref() == Some(api_keys::SUBCOMMAND) {
+        return api_keys::run_cli(std::env::args().skip(1)).await;
+    }
+
     let config = Config::parse();
 
+    let metrics = telemetry::install()?;
+    telemetry::serve(metrics, &config.metrics_addr).await?;
+
     debug!("{:?}", config.db_connection_str);
     let dbopts: PgConnectOptions = config.db_connection_str.parse().expect("couldn't parse DATABASE_URL");
 
@@ -93,10 +171,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     auth_value.set_sensitive(true);
     headers.insert(header::AUTHORIZATION, auth_value);
 
-    let client = Client::builder()
+    let http_client = Client::builder()
         .use_rustls_tls()
         .default_headers(headers)
         .build()?;
+    let traffic = match (config.bgg_record_dir, config.bgg_replay_dir) {
+        (Some(dir), _) => Traffic::Record(dir),
+        (None, Some(dir)) => Traffic::Replay(dir),
+        (None, None) => Traffic::Live,
+    };
+    let client = BggClient::new(http_client, &config.bgg_api_url, traffic);
 
     let bgg_limit = BggLimit(config.bgg_simultaneus_requests);
 
@@ -110,15 +194,31 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     let key_client = key_client_builder.build()?;
-    let key_map = AuthorityMap::from(parse_auth_map(&config.auth_map)).fetch_keys(key_client).await?;
+    let api_keys = ApiKeys::load(pool.clone()).await?;
+    api_keys.spawn_reloader(Duration::from_secs(config.api_key_reload_secs));
+
+    let key_sets = KeySets::fetch(AuthorityMap::from(parse_auth_map(&config.auth_map)), key_client.clone(), api_keys).await?;
+    key_sets.spawn_refresher(Duration::from_secs(config.keyset_refresh_secs));
+
+    let revocations = RevocationLists::new(pool.clone(), key_client, parse_auth_map(&config.revocation_map));
+    revocations.spawn_refresher(Duration::from_secs(config.revocation_refresh_secs));
+
+    let auditor = Auditor::new(pool.clone(), key_sets.clone());
+    auditor.spawn_pruner(config.audit_retention_days);
 
-    debug!("{key_map:?}");
-    let state = AppState{pool, client, bgg_limit, key_map: key_map.clone()};
+    let daily_quota = DailyQuota(config.upstream_daily_quota);
+    let state = AppState{pool, client, bgg_limit, daily_quota, key_sets: key_sets.clone()};
 
-    let rate_key = IpExtractor::trust(config.trust_forwarded_header);
+    let rate_key = UserOrIpExtractor::new(key_sets.clone(), revocations.clone(), IpExtractor::trust(config.trust_forwarded_header));
+    let rate_limits = RateLimits::new(
+        rate_key,
+        ratelimit::parse_tiers(&config.rate_limit_tiers)?,
+        parse_auth_map(&config.rate_limit_authority_tiers),
+    )?;
+    rate_limits.spawn_cleaner();
 
     let app = Router::new()
-        .nest("/api", root_api_router(rate_key, key_map, parse_cors_origins(&config.cors_origins)));
+        .nest("/api", root_api_router(rate_limits, key_sets, revocations, auditor, parse_cors_origins(&config.cors_origins)));
 
     let app = app
         .layer(TraceLayer::new_for_http())
@@ -131,7 +231,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 fn parse_auth_map(cfg: &str) -> Vec<(&str, &str)> {
-    cfg.split(",").map(|mapping| {
+    cfg.split(",").filter(|mapping| !mapping.is_empty()).map(|mapping| {
         let mut pair = mapping.splitn(2, "=");
         let left = pair.next().expect("must have key");
         let right = pair.next().expect("must have value");
@@ -145,25 +245,31 @@ fn parse_cors_origins(cfg: &str) -> Vec<header::HeaderValue> {
     }).collect()
 }
 
-fn root_api_router(extractor: IpExtractor, auth: KeyMap, origin_list: Vec<header::HeaderValue>) -> Router<AppState> {
+fn root_api_router(rate_limits: RateLimits, auth: KeySets, revocations: RevocationLists, auditor: Auditor, origin_list: Vec<header::HeaderValue>) -> Router<AppState> {
     let cors = CorsLayer::new()
         // .max_age(Duration::from_secs(60))
         .allow_credentials(true)
-        .allow_headers([header::AUTHORIZATION, header::ACCEPT])
-        .allow_methods([Method::GET])
+        .allow_headers([header::AUTHORIZATION, header::ACCEPT, header::HeaderName::from_static("x-api-key")])
+        .allow_methods([Method::GET, Method::PUT, Method::POST, Method::DELETE])
+        .expose_headers([
+            header::HeaderName::from_static(search::MISSING_THINGS_HEADER),
+            header::HeaderName::from_static(ratelimit::LIMIT_HEADER),
+            header::HeaderName::from_static(ratelimit::REMAINING_HEADER),
+            header::HeaderName::from_static(ratelimit::RESET_HEADER),
+            header::HeaderName::from_static(ratelimit::POLICY_HEADER),
+            header::RETRY_AFTER,
+        ])
         .allow_origin(origin_list);
 
     open_api_router()
-        .merge(authenticated_router(auth))
+        .merge(authenticated_router(auth.clone(), revocations.clone(), auditor.clone()))
+        .merge(admin_router(auth, revocations, auditor))
         .layer(tower::ServiceBuilder::new()
+            .layer(middleware::from_fn(telemetry::track_requests))
             .layer(CacheControlLayer::new(30))
             .layer(cors)
-            .layer(ratelimiting::layer("api-root", extractor, GovernorConfigBuilder::default()
-                .per_millisecond(200)
-                .burst_size(600)
-            ))
+            .layer(middleware::from_fn_with_state(rate_limits, ratelimit::limit))
         )
-        // XXX key extractor that is either Authentication or SmartIp
 }
 
 fn open_api_router() -> Router<AppState> {
@@ -173,16 +279,51 @@ fn open_api_router() -> Router<AppState> {
         .nest_service(&branding::route(), branding::logos())
 }
 
-fn authenticated_router(auth: KeyMap) -> Router<AppState> {
+fn authenticated_router(auth: KeySets, revocations: RevocationLists, auditor: Auditor) -> Router<AppState> {
+    use biscuits::middleware::check;
+    use auth::Right;
+
     Router::new()
-        .route(&search::route(), get(search::get))
+        .route(&search::route(), get(search::get).layer(check(Right::Search.policy())))
+        .route(&search_events::route(), get(search_events::get).layer(check(Right::Search.policy())))
+        .route(&find::route(), get(find::get).layer(check(Right::Browse.policy())))
+        .route(&quota_resource::route(), get(quota_resource::get))
+        .route(&links::route::<links::CategoryNick>(), get(links::get::<links::CategoryNick>).layer(check(Right::Browse.policy())))
+        .route(&links::route::<links::FamilyNick>(), get(links::get::<links::FamilyNick>).layer(check(Right::Browse.policy())))
+        .route(&links::route::<links::DesignerNick>(), get(links::get::<links::DesignerNick>).layer(check(Right::Browse.policy())))
+        .route(&links::route::<links::PublisherNick>(), get(links::get::<links::PublisherNick>).layer(check(Right::Browse.policy())))
+        .route(&links::route::<links::MechanicNick>(), get(links::get::<links::MechanicNick>).layer(check(Right::Browse.policy())))
         .route(&thing::route(), get(thing::get)
-            .layer(CacheControlLayer::new(86400))
+            .layer(tower::ServiceBuilder::new()
+                .layer(check(Right::Thing.policy()))
+                .layer(CacheControlLayer::new(86400))
+            )
         )
         .layer(tower::ServiceBuilder::new()
-            .layer(biscuits::middleware::setup(auth, "Authorization"))
+            .layer(middleware::from_fn_with_state(auditor, audit::log_request))
+            .layer(middleware::from_fn_with_state(auth.api_keys().clone(), api_keys::authenticate))
+            .layer(middleware::from_fn_with_state(auth.clone(), keysets::refetch_unknown))
+            .layer(biscuits::middleware::setup(auth, auth::AUTH_HEADER))
+            .layer(middleware::from_fn_with_state(revocations, revocation::check_revoked))
             // .layer(middleware::from_fn_with_state(state, authentication::add_rejections))
-            .layer(biscuits::middleware::check(authorizer!(r#"allow if user($user);"#)))
+            // Every route needs a user; most also check for a Right of their own
+            .layer(check(authorizer!(r#"allow if user($user);"#)))
+        )
+}
+
+fn admin_router(auth: KeySets, revocations: RevocationLists, auditor: Auditor) -> Router<AppState> {
+    Router::new()
+        .route(&admin::thing_route(), put(admin::refresh_thing).delete(admin::purge_thing))
+        .route(&admin::link_route(), delete(admin::purge_link))
+        .route(&admin::stats_route(), get(admin::stats))
+        .route(&admin::prefetch_route(), post(admin::start_prefetch))
+        .layer(tower::ServiceBuilder::new()
+            .layer(middleware::from_fn_with_state(auditor, audit::log_request))
+            .layer(middleware::from_fn_with_state(auth.api_keys().clone(), api_keys::authenticate))
+            .layer(middleware::from_fn_with_state(auth.clone(), keysets::refetch_unknown))
+            .layer(biscuits::middleware::setup(auth, auth::AUTH_HEADER))
+            .layer(middleware::from_fn_with_state(revocations, revocation::check_revoked))
+            .layer(biscuits::middleware::check(authorizer!(r#"allow if user($user), admin($user);"#)))
         )
 }
 
@@ -204,19 +345,31 @@ pub enum Error {
     Serialization(#[from] serde_json::Error),
     #[error("Problem with upstream API: ${0:?}")]
     Client(#[from] reqwest::Error),
-    #[error("XML parse error: {0}")]
-    XML(#[from] quick_xml::Error),
-    #[error("Converting API string result into a string: {0}")]
-    ParseInt(#[from] ParseIntError),
     #[error("Did not find expected data in BGG API response")] // go figure
     MalformedResponse,
     #[error("Too many retries, gave up: {0:?}")]
     GivingUp(StatusCode),
     #[error("API server said: {0:?}")]
-    Upstream(StatusCode)
+    Upstream(StatusCode),
+    #[error("Recorded traffic: {0}")]
+    Recording(String),
+    #[error(transparent)]
+    Parse(Box<bgg_api::ParseError>),
+    #[error("BGG reported an error: {1}")]
+    Bgg(bgg_api::BggErrorKind, String),
+    #[error("Couldn't issue a biscuit: {0}")]
+    Biscuit(#[from] biscuit_auth::error::Token),
+    #[error("Daily quota of {0} calls to BGG used up")]
+    QuotaExceeded(i32),
 }
 
 
+impl From<bgg_api::ParseError> for Error {
+    fn from(err: bgg_api::ParseError) -> Self {
+        Self::Parse(Box::new(err))
+    }
+}
+
 impl From<(StatusCode, &'static str)> for Error {
     fn from((code, text): (StatusCode, &'static str)) -> Self {
         Self::StatusCode(code, text.to_string())
@@ -238,12 +391,16 @@ impl IntoResponse for Error {
             Error::StatusCode(c, t) => (c,t).into_response(),
             Error::Job(m) => (StatusCode::INTERNAL_SERVER_ERROR, m).into_response(),
             Error::Serialization(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{:?}", e)).into_response(),
+            Error::Recording(m) => (StatusCode::INTERNAL_SERVER_ERROR, m).into_response(),
+            Error::Biscuit(_) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{self}")).into_response(),
+            Error::QuotaExceeded(_) => (StatusCode::TOO_MANY_REQUESTS, format!("{self}")).into_response(),
+            Error::Bgg(bgg_api::BggErrorKind::InvalidRequest, _) => (StatusCode::BAD_REQUEST, format!("{self}")).into_response(),
             Error::GivingUp(_) |
             Error::Upstream(_) |
             Error::MalformedResponse |
             Error::Client(_) |
-            Error::XML(_) |
-            Error::ParseInt(_) => (StatusCode::BAD_GATEWAY, format!("{self}")).into_response(),
+            Error::Parse(_) |
+            Error::Bgg(bgg_api::BggErrorKind::Upstream, _) => (StatusCode::BAD_GATEWAY, format!("{self}")).into_response(),
         }
     }
 }
@@ -0,0 +1,71 @@
+//! Accounting for the calls to BGG that each user's requests cause, against a daily quota.
+//!
+//! BGG's limits apply to the gateway as a whole, so one user fetching a lot of uncached Things
+//! shouldn't be able to use up everyone's share. Calls are counted in the upstream_usage table;
+//! each user gets the configured default quota, unless upstream_quota gives them their own.
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+use tracing::warn;
+
+use crate::{audit::Tally, auth::Identity, bgg_api, db::{self, UpstreamUsage}, Error};
+
+/// The number of calls to BGG a user may cause per day, unless they have a quota of their own
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct DailyQuota(pub i32);
+
+/// How much of their quota a user has used today
+#[derive(Serialize, Debug)]
+pub(crate) struct Usage {
+    pub used: i32,
+    pub limit: i32,
+    pub remaining: i32,
+    pub endpoints: Vec<UpstreamUsage>,
+}
+
+/// A user's usage of their quota so far today
+pub(crate) async fn usage(db: &Pool<Postgres>, default: DailyQuota, who: &Identity) -> Result<Usage, Error> {
+    let endpoints = db::upstream_usage(db, &who.authority, &who.user).await.map_err(mattak::Error::from)?;
+    let limit = db::upstream_quota(db, &who.authority, &who.user).await.map_err(mattak::Error::from)?
+        .unwrap_or(default.0);
+    let used = endpoints.iter().map(|usage| usage.calls).sum();
+    Ok(Usage{used, limit, remaining: (limit - used).max(0), endpoints})
+}
+
+/// Refuses users who have already used up today's quota.
+/// A request that gets through may still take a user a little past it.
+pub(crate) async fn check(db: &Pool<Postgres>, default: DailyQuota, who: &Identity) -> Result<Usage, Error> {
+    let usage = usage(db, default, who).await?;
+    if usage.remaining == 0 {
+        return Err(Error::QuotaExceeded(usage.limit));
+    }
+    Ok(usage)
+}
+
+/// Who to count a BggClient's calls against, and the request's Tally for the audit log
+#[derive(Clone, Debug)]
+pub(crate) struct Account {
+    db: Pool<Postgres>,
+    identity: Identity,
+    tally: Tally,
+}
+
+impl Account {
+    pub fn new(db: Pool<Postgres>, identity: Identity, tally: Tally) -> Self {
+        Self{db, identity, tally}
+    }
+
+    /// Counts how many of the Things a request wanted were already cached
+    pub fn cache(&self, hits: usize, misses: usize) {
+        self.tally.cache(hits, misses);
+    }
+
+    /// Counts a call, by the endpoint (e.g. "thing" or "search") of the path it was made to.
+    /// Failing to count it isn't worth failing the request over.
+    pub async fn record(&self, path_and_query: &str) {
+        self.tally.upstream_call();
+        let endpoint = bgg_api::endpoint(path_and_query);
+        if let Err(err) = db::record_upstream_call(&self.db, &self.identity.authority, &self.identity.user, endpoint).await {
+            warn!("error recording call to {endpoint} for {:?}: {err:?}", self.identity);
+        }
+    }
+}
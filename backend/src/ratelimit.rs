@@ -0,0 +1,323 @@
+//! Request rate limiting, per biscuit user where there is one, and per client IP otherwise.
+//!
+//! This is mattak's ratelimiting glue, but with a key that can be a user:
+//! keying only on IP lets everyone behind one NAT starve each other.
+//! Limits come in configured tiers. A request gets the tier its biscuit's `tier($tier)` fact
+//! (or its API key) names, or else the one for the authority that issued its biscuit, or else "default".
+//! Responses say where the caller stands in RateLimit-* headers, as in the IETF's draft for them.
+use std::{collections::HashMap, fmt, net::IpAddr, num::NonZeroU32, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Request, State}, http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode}, middleware::Next, response::{IntoResponse, Response}
+};
+use governor::{clock::Clock, middleware::StateInformationMiddleware, DefaultKeyedRateLimiter, Quota};
+use mattak::ratelimiting::IpExtractor;
+use tokio::time::sleep;
+use tower_governor::{key_extractor::KeyExtractor, GovernorError};
+use tracing::{debug, info, warn};
+
+use crate::{auth::{self, Caller, Identity}, keysets::KeySets, revocation::RevocationLists};
+
+/// The tier for requests that don't get one any other way
+pub(crate) const DEFAULT_TIER: &str = "default";
+
+pub(crate) const LIMIT_HEADER: &str = "ratelimit-limit";
+pub(crate) const REMAINING_HEADER: &str = "ratelimit-remaining";
+pub(crate) const RESET_HEADER: &str = "ratelimit-reset";
+pub(crate) const POLICY_HEADER: &str = "ratelimit-policy";
+
+/// How often keys that have recovered their full burst are forgotten
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum RateKey {
+    User(Identity),
+    Ip(IpAddr),
+}
+
+impl fmt::Display for RateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateKey::User(Identity{authority, user}) => write!(f, "{user}@{authority}"),
+            RateKey::Ip(ip) => write!(f, "{ip}"),
+        }
+    }
+}
+
+/// Keys requests on the `user($user)` in their biscuit, falling back to the client's IP
+/// for requests without one (e.g. to the open routes), or whose biscuit we can't read or has been revoked.
+#[derive(Clone, Debug)]
+pub(crate) struct UserOrIpExtractor {
+    keys: KeySets,
+    revocations: RevocationLists,
+    ip: IpExtractor,
+}
+
+impl UserOrIpExtractor {
+    pub fn new(keys: KeySets, revocations: RevocationLists, ip: IpExtractor) -> Self {
+        Self{keys, revocations, ip}
+    }
+
+    /// Who the request's biscuit or API key says it's from, if it has one we can read
+    fn caller(&self, req: &Request) -> Option<Caller> {
+        auth::caller(&self.keys, req.headers(), req.uri())
+    }
+
+    /// The key for a request without a caller: its IP, or if there's none, the response to send instead
+    fn ip_key(&self, req: &Request) -> Result<RateKey, Box<Response>> {
+        self.ip.extract(req)
+            .map(RateKey::Ip)
+            .map_err(|mut err: GovernorError| Box::new(err.as_response()))
+    }
+
+    /// Whether the caller's biscuit has been revoked; if we can't tell, it's treated as though it has
+    async fn revoked(&self, caller: &Caller) -> bool {
+        if caller.revocation_ids.is_empty() {
+            return false;
+        }
+        match self.revocations.revoked(Some(&caller.identity.authority), &caller.revocation_ids).await {
+            Ok(revoked) => !revoked.is_empty(),
+            Err(err) => {
+                warn!("couldn't check whether {} has been revoked, limiting them by IP: {err:?}", caller.identity.user);
+                true
+            }
+        }
+    }
+}
+
+/// Parses tiers configured like `default=200:600,batch=50:2000`:
+/// each tier's name, how many milliseconds it takes to earn another request, and the burst size.
+pub(crate) fn parse_tiers(cfg: &str) -> Result<HashMap<String, Quota>, String> {
+    cfg.split(',').filter(|tier| !tier.is_empty()).map(|tier| {
+        let (name, limits) = tier.split_once('=').ok_or_else(|| format!("tier {tier:?} needs a name=millis:burst"))?;
+        let (millis, burst) = limits.split_once(':').ok_or_else(|| format!("tier {name:?} needs millis:burst"))?;
+        let period = millis.trim().parse().map(Duration::from_millis).map_err(|err| format!("tier {name:?} millis: {err}"))?;
+        let burst: NonZeroU32 = burst.trim().parse().map_err(|err| format!("tier {name:?} burst: {err}"))?;
+        let quota = Quota::with_period(period).ok_or_else(|| format!("tier {name:?} needs more than 0 millis"))?;
+        Ok((name.trim().to_string(), quota.allow_burst(burst)))
+    }).collect()
+}
+
+type Limiter = DefaultKeyedRateLimiter<RateKey, StateInformationMiddleware>;
+
+struct Tier {
+    name: String,
+    quota: Quota,
+    limiter: Limiter,
+}
+
+impl Tier {
+    fn new(name: String, quota: Quota) -> Self {
+        Self{name, quota, limiter: DefaultKeyedRateLimiter::keyed(quota).with_middleware()}
+    }
+
+    /// The RateLimit-* headers, with `reset` as how long until the caller has their whole burst back
+    fn headers(&self, remaining: u32, reset: Duration) -> HeaderMap {
+        let burst = self.quota.burst_size();
+        let window = self.quota.burst_size_replenished_in();
+        [
+            (LIMIT_HEADER, burst.to_string()),
+            (REMAINING_HEADER, remaining.to_string()),
+            (RESET_HEADER, whole_seconds(reset).to_string()),
+            (POLICY_HEADER, format!("{burst};w={}", whole_seconds(window))),
+        ].into_iter()
+            .map(|(name, value)| (HeaderName::from_static(name), HeaderValue::from_str(&value).expect("rate limit headers are ASCII")))
+            .collect()
+    }
+}
+
+fn whole_seconds(duration: Duration) -> u64 {
+    duration.as_millis().div_ceil(1000).try_into().unwrap_or(u64::MAX)
+}
+
+fn check_tiers(tiers: &HashMap<String, Quota>, authorities: &[(&str, &str)]) -> Result<(), String> {
+    if !tiers.contains_key(DEFAULT_TIER) {
+        return Err(format!("no {DEFAULT_TIER:?} rate limit tier configured"));
+    }
+    match authorities.iter().find(|(_, tier)| !tiers.contains_key(*tier)) {
+        Some((host, tier)) => Err(format!("{host} has rate limit tier {tier:?}, which isn't configured")),
+        None => Ok(()),
+    }
+}
+
+/// The configured tiers, each with its own limiter, and which authorities get which
+#[derive(Clone)]
+pub(crate) struct RateLimits {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    extractor: UserOrIpExtractor,
+    tiers: HashMap<String, Tier>,
+    /// Authority host => tier name
+    authorities: HashMap<String, String>,
+}
+
+impl RateLimits {
+    /// Every tier an authority maps to has to be configured, as does the default tier
+    pub fn new(extractor: UserOrIpExtractor, tiers: HashMap<String, Quota>, authorities: Vec<(&str, &str)>) -> Result<Self, String> {
+        check_tiers(&tiers, &authorities)?;
+        for (name, quota) in &tiers {
+            info!("rate limit tier {name}: bursts of {} requests, one more every {:?}", quota.burst_size(), quota.replenish_interval());
+        }
+        let tiers = tiers.into_iter().map(|(name, quota)| (name.clone(), Tier::new(name, quota))).collect();
+        let authorities = authorities.into_iter().map(|(host, tier)| (host.to_string(), tier.to_string())).collect();
+        Ok(Self{inner: Arc::new(Inner{extractor, tiers, authorities})})
+    }
+
+    /// Forgets callers who've been quiet long enough to have their whole burst back, periodically
+    pub fn spawn_cleaner(&self) {
+        let limits = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(CLEANUP_INTERVAL).await;
+                for tier in limits.inner.tiers.values() {
+                    tier.limiter.retain_recent();
+                    debug!("rate limiting storage size for tier {}: {}", tier.name, tier.limiter.len());
+                }
+            }
+        });
+    }
+
+    /// The tier a caller claims, if it's configured; else that of the authority that issued their biscuit;
+    /// else the default one
+    fn tier(&self, claimed: Option<&str>, authority: Option<&str>) -> &Tier {
+        let tiers = &self.inner.tiers;
+        claimed.and_then(|name| {
+            let tier = tiers.get(name);
+            if tier.is_none() {
+                debug!("unconfigured rate limit tier {name:?} claimed");
+            }
+            tier
+        })
+            .or_else(|| authority.and_then(|host| self.inner.authorities.get(host)).and_then(|name| tiers.get(name)))
+            .unwrap_or_else(|| &tiers[DEFAULT_TIER])
+    }
+}
+
+/// Middleware: refuses requests beyond their tier's limits with a 429,
+/// and tells every caller where they stand.
+pub(crate) async fn limit(State(limits): State<RateLimits>, req: Request, next: Next) -> Response {
+    let extractor = &limits.inner.extractor;
+    let (key, claimed) = match extractor.caller(&req) {
+        Some(caller) if !extractor.revoked(&caller).await => (RateKey::User(caller.identity), caller.tier),
+        _ => match extractor.ip_key(&req) {
+            Ok(key) => (key, None),
+            Err(response) => return *response,
+        },
+    };
+    // Only an authority that's vouched for the caller picks their tier: the headers naming it are anyone's to send
+    let authority = match &key {
+        RateKey::User(identity) => Some(identity.authority.as_str()),
+        RateKey::Ip(_) => None,
+    };
+    let tier = limits.tier(claimed.as_deref(), authority);
+
+    match tier.limiter.check_key(&key) {
+        Ok(state) => {
+            let remaining = state.remaining_burst_capacity();
+            let spent = tier.quota.burst_size().get().saturating_sub(remaining);
+            let mut response = next.run(req).await;
+            response.headers_mut().extend(tier.headers(remaining, tier.quota.replenish_interval() * spent));
+            response
+        }
+        Err(not_until) => {
+            let wait = not_until.wait_time_from(tier.limiter.clock().now());
+            debug!("rate limiting {key} in tier {} for {wait:?}", tier.name);
+            metrics::counter!("rate_limit_rejections_total", "tier" => tier.name.clone()).increment(1);
+            let mut headers = tier.headers(0, wait);
+            headers.insert(header::RETRY_AFTER, whole_seconds(wait).into());
+            (StatusCode::TOO_MANY_REQUESTS, headers, format!("Too many requests, try again in {}s", whole_seconds(wait))).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(tiers: &str, authorities: &[(&str, &str)]) -> Result<HashMap<String, Quota>, String> {
+        let tiers = parse_tiers(tiers)?;
+        check_tiers(&tiers, authorities)?;
+        Ok(tiers)
+    }
+
+    #[test]
+    fn parsing_tiers() {
+        let tiers = limits("default=200:600, batch=50:2000", &[("example.com", "batch")]).expect("valid tiers");
+        assert_eq!(tiers["default"].replenish_interval(), Duration::from_millis(200));
+        assert_eq!(tiers["default"].burst_size().get(), 600);
+        assert_eq!(tiers["batch"].burst_size().get(), 2000);
+
+        assert!(parse_tiers("default=200").is_err());
+        assert!(parse_tiers("default=0:10").is_err());
+        assert!(parse_tiers("default=200:0").is_err());
+        assert!(limits("batch=50:2000", &[]).is_err());
+        assert!(limits("default=200:600", &[("example.com", "batch")]).is_err());
+    }
+
+    #[test]
+    fn limiting_within_a_tier() {
+        let tier = Tier::new("tiny".to_string(), Quota::with_period(Duration::from_secs(10)).expect("period").allow_burst(NonZeroU32::new(2).expect("burst")));
+        let alice = RateKey::Ip("10.0.0.1".parse().expect("ip"));
+        let bob = RateKey::Ip("10.0.0.2".parse().expect("ip"));
+
+        assert_eq!(tier.limiter.check_key(&alice).expect("first").remaining_burst_capacity(), 1);
+        assert_eq!(tier.limiter.check_key(&alice).expect("second").remaining_burst_capacity(), 0);
+        assert!(tier.limiter.check_key(&alice).is_err());
+        assert!(tier.limiter.check_key(&bob).is_ok());
+
+        let headers = tier.headers(0, Duration::from_millis(9500));
+        assert_eq!(headers[LIMIT_HEADER], "2");
+        assert_eq!(headers[REMAINING_HEADER], "0");
+        assert_eq!(headers[RESET_HEADER], "10");
+        assert_eq!(headers[POLICY_HEADER], "2;w=20");
+    }
+
+    /// Tests against a database, for checking revocations
+    mod db {
+        use axum::{body::Body, middleware, routing::get, Router};
+        use biscuit_auth::{macros::biscuit, KeyPair};
+        use sqlx::query;
+        use tower::ServiceExt;
+
+        use super::super::*;
+        use crate::{db::testing::TestSchema, keysets::tests::{headers, StubAuthority, HOST_NAME}};
+
+        #[tokio::test]
+        async fn authority_tiers_need_an_unrevoked_biscuit() {
+            let schema = TestSchema::new().await;
+            let root = KeyPair::new();
+            let authority = StubAuthority::start(vec![root.public()]).await;
+            let revocations = RevocationLists::new(schema.pool.clone(), reqwest::Client::new(), vec![]);
+            let extractor = UserOrIpExtractor::new(authority.key_sets().await, revocations, IpExtractor::trust(true));
+            let tiers = parse_tiers("default=1000:2,generous=10:1000").expect("valid tiers");
+            let limits = RateLimits::new(extractor, tiers, vec![(HOST_NAME, "generous")]).expect("rate limits");
+            let app = Router::new().route("/api/thing", get(|| async { "ok" }))
+                .layer(middleware::from_fn_with_state(limits, limit));
+            let limit_for = |headers: HeaderMap| {
+                let mut req = Request::get("/api/thing").body(Body::empty()).expect("request");
+                *req.headers_mut() = headers;
+                req.headers_mut().insert("x-forwarded-for", HeaderValue::from_static("10.0.0.1"));
+                let app = app.clone();
+                async move { app.oneshot(req).await.expect("response").headers()[LIMIT_HEADER].clone() }
+            };
+
+            let token = biscuit!(r#"user("alice");"#).build(&root).expect("build biscuit");
+            assert_eq!(limit_for(headers(&token)).await, "1000");
+
+            // Anyone can say which host they were asking
+            let mut spoofed = HeaderMap::new();
+            spoofed.insert("x-forwarded-host", HeaderValue::from_static(HOST_NAME));
+            assert_eq!(limit_for(spoofed).await, "2");
+
+            let revoked = biscuit!(r#"user("mallory"); tier("generous");"#).build(&root).expect("build biscuit");
+            query(r#"insert into revoked_biscuit (revocation_id, reason) values (replace(encode($1, 'base64'), E'\n', ''), 'leaked')"#)
+                .bind(revoked.revocation_identifiers().remove(0))
+                .execute(&schema.pool).await.expect("revoke biscuit");
+            assert_eq!(limit_for(headers(&revoked)).await, "2");
+
+            schema.drop().await;
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! Cache management, for biscuits with an `admin($user)` fact.
+//! BGG calls made from here are counted against the admin's usage, but not limited by their quota.
+use axum::{debug_handler, extract::State, response::IntoResponse, Json};
+use mattak::{
+    hypermedia::{op, ActionType, Operation, ResourceFields},
+    routing::{extract::{ExtractedRoute as _, NestedRoute}, Route as _}
+};
+use mattak_derives::Route;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    audit::Tally, auth::Identity,
+    bgg_api::{fetch_things, prefetch, BggClient},
+    db::{self, AgeBucket, BggCategory, BggDesigner, BggFamily, BggMechanic, BggPublisher, CacheCounts, CategoryId, DesignerId, FamilyId, LinkEntity, MechanicId, PublisherId, ThingData},
+    quota::Account,
+    AppState, BggLimit, Error
+};
+
+use super::OptionalParam;
+
+fn delete_op() -> Operation {
+    Operation{method: axum::http::Method::DELETE.into(), r#type: "DeleteAction".to_string()}
+}
+
+fn id_list(ids: &str) -> Vec<String> {
+    ids.split(',').map(str::trim).filter(|id| !id.is_empty()).map(str::to_string).collect()
+}
+
+/// A cached Thing: PUT refetches it from BGG, even if BGG said it had no such Thing; DELETE purges it.
+#[derive(Route, Clone, Default, Serialize, Deserialize)]
+#[template("/admin/thing/{bgg_id}")]
+pub(crate) struct ThingNick {
+    bgg_id: String,
+}
+
+pub(crate) fn thing_route() -> String {
+    ThingNick::axum_route()
+}
+
+#[derive(Serialize)]
+struct ThingResponse {
+    #[serde(flatten)]
+    resource_fields: ResourceFields<ThingNick>,
+    thing: ThingData,
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn refresh_thing(
+    State(db): State<Pool<Postgres>>,
+    State(client): State<BggClient>,
+    who: Identity,
+    tally: Tally,
+    req: NestedRoute<ThingNick>
+) -> Result<impl IntoResponse, Error> {
+    let ids = vec![req.nick.bgg_id.clone()];
+    db::forget_missing(&db, &ids).await.map_err(mattak::Error::from)?;
+    let client = client.on_behalf_of(Account::new(db.clone(), who, tally));
+    let things = fetch_things(client, db, ids).await?;
+
+    let thing = things.into_iter().next()
+        .ok_or_else(|| Error::StatusCode(StatusCode::NOT_FOUND, format!("BGG has no Thing with id {}", req.nick.bgg_id)))?;
+    Ok((StatusCode::OK, Json(ThingResponse{
+        resource_fields: req.resource_fields("api:adminThing", vec![op(ActionType::Update), delete_op()])?,
+        thing: thing.data,
+    })))
+}
+
+#[derive(Serialize)]
+struct PurgeResponse {
+    purged: Vec<String>,
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn purge_thing(
+    State(db): State<Pool<Postgres>>,
+    req: NestedRoute<ThingNick>
+) -> Result<impl IntoResponse, Error> {
+    let purged = db::purge_things(&db, std::slice::from_ref(&req.nick.bgg_id)).await.map_err(mattak::Error::from)?;
+    if purged.is_empty() {
+        return Err(Error::StatusCode(StatusCode::NOT_FOUND, format!("No Thing {} in the cache", req.nick.bgg_id)));
+    }
+    Ok((StatusCode::OK, Json(PurgeResponse{purged})))
+}
+
+/// A cached link entity: DELETE purges it, unlinking it from its Things.
+/// kind is one of category, family, designer, publisher, mechanic or tag.
+/// Tags also need their link_type (e.g. rpggenre), since their ids are only unique within one.
+#[derive(Route, Clone, Default, Serialize, Deserialize)]
+#[template("/admin/links/{kind}/{bgg_id}{?link_type}")]
+pub(crate) struct LinkNick {
+    kind: String,
+    bgg_id: String,
+    link_type: OptionalParam<String>,
+}
+
+pub(crate) fn link_route() -> String {
+    LinkNick::axum_route()
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn purge_link(
+    State(db): State<Pool<Postgres>>,
+    req: NestedRoute<LinkNick>
+) -> Result<impl IntoResponse, Error> {
+    async fn purge<L: LinkEntity>(db: &Pool<Postgres>, bgg_id: &str) -> Result<bool, Error> {
+        Ok(db::purge_link::<L, _>(db, bgg_id).await.map_err(mattak::Error::from)?)
+    }
+
+    let LinkNick{kind, bgg_id, link_type} = &req.nick;
+    if link_type.is_some() && kind != "tag" {
+        return Err(Error::StatusCode(StatusCode::BAD_REQUEST, format!("Only tags have a link_type, not {kind}")));
+    }
+    let purged = match kind.as_str() {
+        "category" => purge::<BggCategory<CategoryId>>(&db, bgg_id).await?,
+        "family" => purge::<BggFamily<FamilyId>>(&db, bgg_id).await?,
+        "designer" => purge::<BggDesigner<DesignerId>>(&db, bgg_id).await?,
+        "publisher" => purge::<BggPublisher<PublisherId>>(&db, bgg_id).await?,
+        "mechanic" => purge::<BggMechanic<MechanicId>>(&db, bgg_id).await?,
+        "tag" => {
+            let link_type = link_type.as_deref()
+                .ok_or_else(|| Error::StatusCode(StatusCode::BAD_REQUEST, "Tags need a link_type".to_string()))?;
+            db::purge_tag(&db, link_type, bgg_id).await.map_err(mattak::Error::from)?
+        }
+        _ => return Err(Error::StatusCode(StatusCode::NOT_FOUND, format!("No such kind of link: {kind}"))),
+    };
+    if !purged {
+        return Err(Error::StatusCode(StatusCode::NOT_FOUND, format!("No {kind} {bgg_id} in the cache")));
+    }
+    Ok((StatusCode::OK, Json(PurgeResponse{purged: vec![bgg_id.clone()]})))
+}
+
+/// What's in the cache, and how old it is
+#[derive(Route, Clone, Default, Serialize, Deserialize)]
+#[template("/admin/stats")]
+pub(crate) struct StatsNick {}
+
+pub(crate) fn stats_route() -> String {
+    StatsNick::axum_route()
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    #[serde(flatten)]
+    resource_fields: ResourceFields<StatsNick>,
+    #[serde(flatten)]
+    counts: CacheCounts,
+    ages: Vec<AgeBucket>,
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn stats(
+    State(db): State<Pool<Postgres>>,
+    req: NestedRoute<StatsNick>
+) -> Result<impl IntoResponse, Error> {
+    let counts = db::cache_counts(&db).await.map_err(mattak::Error::from)?;
+    let ages = db::cache_ages(&db).await.map_err(mattak::Error::from)?;
+    Ok((StatusCode::OK, Json(StatsResponse{
+        resource_fields: req.resource_fields("api:adminStats", vec![op(ActionType::View)])?,
+        counts,
+        ages,
+    })))
+}
+
+/// POST to fetch Things from BGG into the cache in the background, by comma-separated ids
+#[derive(Route, Clone, Default, Serialize, Deserialize)]
+#[template("/admin/prefetch{?ids}")]
+pub(crate) struct PrefetchNick {
+    ids: String,
+}
+
+pub(crate) fn prefetch_route() -> String {
+    PrefetchNick::axum_route()
+}
+
+#[derive(Serialize)]
+struct PrefetchResponse {
+    prefetching: Vec<String>,
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn start_prefetch(
+    State(db): State<Pool<Postgres>>,
+    State(client): State<BggClient>,
+    State(bgg_limit): State<BggLimit>,
+    who: Identity,
+    tally: Tally,
+    req: NestedRoute<PrefetchNick>
+) -> Result<impl IntoResponse, Error> {
+    let ids = id_list(&req.nick.ids);
+    if ids.is_empty() {
+        return Err(Error::StatusCode(StatusCode::BAD_REQUEST, "No ids to prefetch".to_string()));
+    }
+    let client = client.on_behalf_of(Account::new(db.clone(), who, tally));
+    prefetch(client, db, ids.clone(), bgg_limit.into());
+    Ok((StatusCode::ACCEPTED, Json(PrefetchResponse{prefetching: ids})))
+}
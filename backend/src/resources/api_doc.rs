@@ -5,7 +5,7 @@ use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::{resources, AppState, Error};
+use crate::{auth::Right, resources, AppState, Error};
 
 
 #[derive(Route, Clone, Default, Serialize, Deserialize)]
@@ -31,8 +31,44 @@ pub(crate) async fn get(
         "search": req
             .default_relative_route::<resources::search::Nick>("")
             .affordance("search", vec![op(Find)]),
+        "search_events": req
+            .default_relative_route::<resources::search_events::Nick>("")
+            .affordance("searchEvents", vec![op(Find)]),
+        "find_things": req
+            .default_relative_route::<resources::find::Nick>("")
+            .affordance("findThings", vec![op(Find)]),
+        "quota": req
+            .default_relative_route::<resources::quota::Nick>("")
+            .affordance("quota", vec![op(View)]),
         "thing": req
             .default_relative_route::<resources::thing::Nick>("")
-            .affordance("thing", vec![op(View)])
+            .affordance("thing", vec![op(View)]),
+        "category": req
+            .default_relative_route::<resources::links::CategoryNick>("")
+            .affordance("category", vec![op(View)]),
+        "family": req
+            .default_relative_route::<resources::links::FamilyNick>("")
+            .affordance("family", vec![op(View)]),
+        "designer": req
+            .default_relative_route::<resources::links::DesignerNick>("")
+            .affordance("designer", vec![op(View)]),
+        "publisher": req
+            .default_relative_route::<resources::links::PublisherNick>("")
+            .affordance("publisher", vec![op(View)]),
+        "mechanic": req
+            .default_relative_route::<resources::links::MechanicNick>("")
+            .affordance("mechanic", vec![op(View)]),
+        // Besides a user, the biscuit has to grant these (or right("bgg", "*")) for each affordance
+        "required_rights": {
+            "search": Right::Search.fact(),
+            "search_events": Right::Search.fact(),
+            "find_things": Right::Browse.fact(),
+            "thing": Right::Thing.fact(),
+            "category": Right::Browse.fact(),
+            "family": Right::Browse.fact(),
+            "designer": Right::Browse.fact(),
+            "publisher": Right::Browse.fact(),
+            "mechanic": Right::Browse.fact(),
+        }
     }))))
 }
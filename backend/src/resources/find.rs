@@ -0,0 +1,105 @@
+use axum::{debug_handler, extract::State, response::IntoResponse, Json};
+use mattak::{hypermedia::{op, ActionType, ResourceFields}, routing::{extract::{ExtractedRoute as _, NestedRoute}, Route as _}};
+use mattak_derives::Route;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+use crate::{db::{BggThing, ThingData, ThingFilter, ThingOrder}, AppState, Error};
+
+use super::{paging, OptionalParam};
+
+/// Finds Things in our cache, rather than asking BGG.
+///
+/// min_players and max_players describe the group: a Thing matches if it can be played
+/// by anywhere from min_players to max_players players.
+/// min_duration and max_duration are in minutes; age matches Things suitable for players that old.
+/// category, family, designer, mechanic and publisher take BGG ids.
+/// sort is one of name, year, duration or players, prefixed with "-" to reverse it.
+#[derive(Route, Clone, Default, Serialize, Deserialize)]
+#[template("/things/find{?min_players,max_players,min_duration,max_duration,year_from,year_to,age,category,family,designer,mechanic,publisher,sort,page,per_page}")]
+pub(crate) struct Nick {
+    min_players: OptionalParam<i32>,
+    max_players: OptionalParam<i32>,
+    min_duration: OptionalParam<i32>,
+    max_duration: OptionalParam<i32>,
+    year_from: OptionalParam<i32>,
+    year_to: OptionalParam<i32>,
+    age: OptionalParam<i32>,
+    category: OptionalParam<String>,
+    family: OptionalParam<String>,
+    designer: OptionalParam<String>,
+    mechanic: OptionalParam<String>,
+    publisher: OptionalParam<String>,
+    sort: OptionalParam<String>,
+    page: OptionalParam<usize>,
+    per_page: OptionalParam<usize>,
+}
+
+pub(crate) fn route() -> String {
+    Nick::axum_route()
+}
+
+impl Nick {
+    fn filter(&self) -> ThingFilter {
+        ThingFilter {
+            min_players: *self.min_players,
+            max_players: *self.max_players,
+            min_duration: *self.min_duration,
+            max_duration: *self.max_duration,
+            year_from: *self.year_from,
+            year_to: *self.year_to,
+            age: *self.age,
+            category: self.category.clone().into(),
+            family: self.family.clone().into(),
+            designer: self.designer.clone().into(),
+            mechanic: self.mechanic.clone().into(),
+            publisher: self.publisher.clone().into(),
+        }
+    }
+
+    fn order(&self) -> Result<(ThingOrder, bool), Error> {
+        let sort = self.sort.as_deref().unwrap_or("name");
+        let (name, descending) = match sort.strip_prefix('-') {
+            Some(name) => (name, true),
+            None => (sort, false),
+        };
+        let order = match name {
+            "name" => ThingOrder::Name,
+            "year" => ThingOrder::Year,
+            "duration" => ThingOrder::Duration,
+            "players" => ThingOrder::Players,
+            _ => return Err(Error::StatusCode(StatusCode::BAD_REQUEST, format!("can't sort by {name:?}"))),
+        };
+        Ok((order, descending))
+    }
+}
+
+#[derive(Serialize)]
+struct Response {
+    #[serde(flatten)]
+    resource_fields: ResourceFields<Nick>,
+    total: i64,
+    things: Vec<ThingData>
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn get(
+    State(db): State<Pool<Postgres>>,
+    req: NestedRoute<Nick>
+) -> Result<impl IntoResponse, Error> {
+    let (order, descending) = req.nick.order()?;
+    let (page, per_page) = paging(req.nick.page, req.nick.per_page);
+    let offset = (page - 1).checked_mul(per_page).and_then(|offset| i64::try_from(offset).ok())
+        .ok_or_else(|| Error::StatusCode(StatusCode::BAD_REQUEST, format!("page {page} is out of range")))?;
+
+    let (total, things) = BggThing::find(&db, &req.nick.filter(), order, descending, per_page as i64, offset)
+        .await
+        .map_err(mattak::Error::from)?;
+
+    Ok((StatusCode::OK, Json(Response{
+        resource_fields: req.resource_fields("api:findThings", vec![op(ActionType::Find)])?,
+        total,
+        things: things.into_iter().map(|thing| thing.data).collect(),
+    })))
+}
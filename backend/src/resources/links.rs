@@ -0,0 +1,149 @@
+use axum::{extract::State, response::IntoResponse, Json};
+use mattak::{
+    hypermedia::{op, ActionType, Affordance, ResourceFields},
+    routing::{extract::{ExtractedRoute as _, NestedRoute}, FillPolicy, Route}
+};
+use mattak_derives::Route;
+use reqwest::StatusCode;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    db::{get_link, BggCategory, BggDesigner, BggFamily, BggMechanic, BggPublisher, BggThing, CategoryId, DesignerId, FamilyId, LinkData, LinkEntity, MechanicId, PublisherId, TagData, ThingData, ThingLinks},
+    Error
+};
+
+/// The resources for the things a Thing links to: categories, families and so on.
+/// Each lists the cached Things that link to it.
+pub(crate) trait LinkNick: Route + Clone + Serialize + DeserializeOwned + Send + Sync + 'static {
+    type Entity: LinkEntity;
+    const API_NAME: &'static str;
+
+    fn new(bgg_id: String) -> Self;
+    fn bgg_id(&self) -> &str;
+}
+
+macro_rules! link_nick {
+    ($nick:ident, $entity:ty, $api_name:literal) => {
+        impl LinkNick for $nick {
+            type Entity = $entity;
+            const API_NAME: &'static str = $api_name;
+
+            fn new(bgg_id: String) -> Self {
+                Self{bgg_id}
+            }
+
+            fn bgg_id(&self) -> &str {
+                &self.bgg_id
+            }
+        }
+    };
+}
+
+#[derive(Route, Clone, Default, Serialize, Deserialize)]
+#[template("/category/{bgg_id}")]
+pub(crate) struct CategoryNick {
+    bgg_id: String,
+}
+link_nick!(CategoryNick, BggCategory<CategoryId>, "api:categoryDetail");
+
+#[derive(Route, Clone, Default, Serialize, Deserialize)]
+#[template("/family/{bgg_id}")]
+pub(crate) struct FamilyNick {
+    bgg_id: String,
+}
+link_nick!(FamilyNick, BggFamily<FamilyId>, "api:familyDetail");
+
+#[derive(Route, Clone, Default, Serialize, Deserialize)]
+#[template("/designer/{bgg_id}")]
+pub(crate) struct DesignerNick {
+    bgg_id: String,
+}
+link_nick!(DesignerNick, BggDesigner<DesignerId>, "api:designerDetail");
+
+#[derive(Route, Clone, Default, Serialize, Deserialize)]
+#[template("/publisher/{bgg_id}")]
+pub(crate) struct PublisherNick {
+    bgg_id: String,
+}
+link_nick!(PublisherNick, BggPublisher<PublisherId>, "api:publisherDetail");
+
+#[derive(Route, Clone, Default, Serialize, Deserialize)]
+#[template("/mechanic/{bgg_id}")]
+pub(crate) struct MechanicNick {
+    bgg_id: String,
+}
+link_nick!(MechanicNick, BggMechanic<MechanicId>, "api:mechanicDetail");
+
+pub(crate) fn route<N: LinkNick>() -> String {
+    N::axum_route()
+}
+
+#[derive(Serialize)]
+struct Response<N: LinkNick> {
+    #[serde(flatten)]
+    resource_fields: ResourceFields<N>,
+    #[serde(flatten)]
+    link: LinkData,
+    things: Vec<ThingData>,
+}
+
+pub(crate) async fn get<N: LinkNick>(
+    State(db): State<Pool<Postgres>>,
+    req: NestedRoute<N>
+) -> Result<impl IntoResponse, Error> {
+    let entity: N::Entity = get_link(&db, req.nick.bgg_id())
+        .await
+        .map_err(mattak::Error::from)?;
+    let things = BggThing::get_linked_to(&db, &entity)
+        .await
+        .map_err(mattak::Error::from)?;
+
+    Ok((StatusCode::OK, Json(Response{
+        resource_fields: req.resource_fields(N::API_NAME, vec![op(ActionType::View)])?,
+        link: entity.data().clone(),
+        things: things.into_iter().map(|thing| thing.data).collect(),
+    })))
+}
+
+/// A link from a Thing, along with where to find out more about it
+#[derive(Serialize)]
+pub(crate) struct LinkedEntity {
+    #[serde(flatten)]
+    data: LinkData,
+    link: Affordance,
+}
+
+#[derive(Serialize)]
+pub(crate) struct LinkAffordances {
+    categories: Vec<LinkedEntity>,
+    families: Vec<LinkedEntity>,
+    designers: Vec<LinkedEntity>,
+    publishers: Vec<LinkedEntity>,
+    mechanics: Vec<LinkedEntity>,
+    tags: Vec<TagData>,
+}
+
+fn linked<R, N: LinkNick>(req: &NestedRoute<R>, links: &[LinkData]) -> Result<Vec<LinkedEntity>, Error> {
+    links.iter().map(|data| {
+        let nick = N::new(data.bgg_id.clone());
+        let id = req.relative_route("", nick.clone()).entry().serialize(FillPolicy::Strict, nick)?;
+        Ok(LinkedEntity{
+            data: data.clone(),
+            link: Affordance::Link{id, operation: vec![op(ActionType::View)]},
+        })
+    }).collect::<Result<Vec<_>, mattak::Error>>()
+        .map_err(Error::from)
+}
+
+/// Builds the links from a Thing to the resources for the things it links to
+pub(crate) fn affordances<R>(req: &NestedRoute<R>, links: &ThingLinks) -> Result<LinkAffordances, Error> {
+    Ok(LinkAffordances{
+        categories: linked::<R, CategoryNick>(req, &links.categories)?,
+        families: linked::<R, FamilyNick>(req, &links.families)?,
+        designers: linked::<R, DesignerNick>(req, &links.designers)?,
+        publishers: linked::<R, PublisherNick>(req, &links.publishers)?,
+        mechanics: linked::<R, MechanicNick>(req, &links.mechanics)?,
+        tags: links.tags.clone(),
+    })
+}
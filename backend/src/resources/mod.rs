@@ -1,4 +1,65 @@
+use std::{fmt::Display, ops::Deref, str::FromStr};
+
+use serde::{de, Deserialize, Deserializer, Serialize};
+
 pub(super) mod api_doc;
 pub(super) mod search;
+pub(super) mod search_events;
 pub(super) mod thing;
 pub(super) mod branding;
+pub(super) mod find;
+pub(super) mod links;
+pub(super) mod quota;
+pub(super) mod admin;
+
+const DEFAULT_PER_PAGE: usize = 20;
+const MAX_PER_PAGE: usize = 100;
+
+/// Resolves the requested page and page size, applying defaults and limits.
+pub(super) fn paging(page: OptionalParam<usize>, per_page: OptionalParam<usize>) -> (usize, usize) {
+    (
+        page.unwrap_or(1).max(1),
+        per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE)
+    )
+}
+
+/// A query parameter that may be left out of a request.
+///
+/// Route extraction hands us an empty string for missing query variables,
+/// which won't parse as anything but a string, so this treats "" as None.
+/// When serialized for a URI, None leaves the variable out.
+#[derive(Clone, Copy, Default, Debug, Serialize)]
+#[serde(transparent)]
+pub(crate) struct OptionalParam<T>(Option<T>);
+
+impl<T> Deref for OptionalParam<T> {
+    type Target = Option<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> From<Option<T>> for OptionalParam<T> {
+    fn from(value: Option<T>) -> Self {
+        Self(value)
+    }
+}
+
+impl<T> From<OptionalParam<T>> for Option<T> {
+    fn from(value: OptionalParam<T>) -> Self {
+        value.0
+    }
+}
+
+impl<'de, T> Deserialize<'de> for OptionalParam<T>
+where T: FromStr, <T as FromStr>::Err: Display {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        if raw.is_empty() {
+            Ok(Self(None))
+        } else {
+            raw.parse().map(|v| Self(Some(v))).map_err(de::Error::custom)
+        }
+    }
+}
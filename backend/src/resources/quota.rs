@@ -0,0 +1,44 @@
+use axum::{debug_handler, extract::State, response::IntoResponse, Json};
+use mattak::{hypermedia::{op, ActionType, ResourceFields}, routing::{extract::{ExtractedRoute as _, NestedRoute}, Route as _}};
+use mattak_derives::Route;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+use crate::{auth::Identity, quota::{self, DailyQuota, Usage}, AppState, Error};
+
+/// How many of today's calls to BGG the requesting user has used, and on what.
+#[derive(Route, Clone, Default, Serialize, Deserialize)]
+#[template("/quota")]
+pub(crate) struct Nick {}
+
+pub(crate) fn route() -> String {
+    Nick::axum_route()
+}
+
+#[derive(Serialize)]
+struct Response {
+    #[serde(flatten)]
+    resource_fields: ResourceFields<Nick>,
+    user: String,
+    authority: String,
+    #[serde(flatten)]
+    usage: Usage,
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn get(
+    State(db): State<Pool<Postgres>>,
+    State(daily_quota): State<DailyQuota>,
+    who: Identity,
+    req: NestedRoute<Nick>
+) -> Result<impl IntoResponse, Error> {
+    let usage = quota::usage(&db, daily_quota, &who).await?;
+
+    Ok((StatusCode::OK, Json(Response{
+        resource_fields: req.resource_fields("api:upstreamQuota", vec![op(ActionType::View)])?,
+        user: who.user,
+        authority: who.authority,
+        usage,
+    })))
+}
@@ -2,19 +2,26 @@ use mattak::routing::{extract::{ExtractedRoute as _, NestedRoute}, Route as _};
 use axum::{debug_handler, extract::State, response::IntoResponse, Json};
 use mattak::hypermedia::{op, ActionType, ResourceFields};
 use mattak_derives::Route;
-use reqwest::{Client, StatusCode};
+use reqwest::{header::{self, HeaderMap, HeaderValue}, StatusCode};
 use serde::{Serialize, Deserialize};
 use sqlx::{Pool, Postgres};
 
 use crate::{
-    bgg_api::{search, SearchItem}, db::ThingData, AppState, BggLimit, Error
+    audit::Tally, auth::Identity, bgg_api::{search, BggClient, MissingThing, SearchItem, SearchPage}, db::ThingData, quota::{self, Account, DailyQuota}, AppState, BggLimit, Error
 };
 
+use super::{paging, OptionalParam};
+
+
+/// Set to the number of items whose details are missing from a partial response
+pub(crate) const MISSING_THINGS_HEADER: &str = "x-missing-things";
 
 #[derive(Route, Clone, Default, Serialize, Deserialize)]
-#[template("/search{?query}")]
+#[template("/search{?query,page,per_page}")]
 pub(crate) struct Nick {
     query: String,
+    page: OptionalParam<usize>,
+    per_page: OptionalParam<usize>,
 }
 
 pub(crate) fn route() -> String {
@@ -25,30 +32,47 @@ pub(crate) fn route() -> String {
 struct Response {
     #[serde(flatten)]
     resource_fields: ResourceFields<Nick>,
+    total: usize,
     items: Vec<SearchItem>,
-    things: Vec<ThingData>
+    things: Vec<ThingData>,
+    /// Items on this page whose details couldn't be fetched from BGG
+    missing: Vec<MissingThing>,
 }
 
 #[debug_handler(state = AppState)]
 pub(crate) async fn get(
     State(db): State<Pool<Postgres>>,
-    State(client): State<Client>,
+    State(client): State<BggClient>,
+    State(daily_quota): State<DailyQuota>,
+    who: Identity,
+    tally: Tally,
     State(bgg_limit): State<BggLimit>,
     req: NestedRoute<Nick>
 ) -> Result<impl IntoResponse, Error> {
-    let (items, things) = search(client, &db, req.nick.query.clone(), bgg_limit.into()).await?;
+    quota::check(&db, daily_quota, &who).await?;
+    let client = client.on_behalf_of(Account::new(db.clone(), who, tally));
+    let (page, per_page) = paging(req.nick.page, req.nick.per_page);
+    let SearchPage{total, items, things, missing} = search(client, &db, req.nick.query.clone(), page, per_page, bgg_limit.into()).await?;
 
     let  response = Response{
         resource_fields: req.resource_fields(
             "api:searchThings",
             vec![ op(ActionType::View)]
         )?,
+        total,
         items,
         things,
+        missing,
     };
 
+    // Partial results shouldn't be cached as though they were complete
+    let mut headers = HeaderMap::new();
+    if !response.missing.is_empty() {
+        headers.insert(MISSING_THINGS_HEADER, response.missing.len().into());
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    }
 
-    Ok((StatusCode::OK,Json(response)))
+    Ok((StatusCode::OK, headers, Json(response)))
 }
 
 
@@ -62,3 +86,4 @@ pub(crate) async fn get(
 // subtype=boardgame for the expansions. Workaround is to use
 // excludesubtype=boardgameexpansion and make a 2nd call asking for
 // subtype=boardgameexpansion
+
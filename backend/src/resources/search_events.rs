@@ -0,0 +1,102 @@
+use std::convert::Infallible;
+
+use axum::{debug_handler, extract::State, response::{sse::{Event, KeepAlive, Sse}, IntoResponse}};
+use mattak::{hypermedia::{op, ActionType, ResourceFields}, routing::{extract::{ExtractedRoute as _, NestedRoute}, Route as _}};
+use mattak_derives::Route;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::debug;
+
+use crate::{
+    audit::Tally, auth::Identity, bgg_api::{start_search, BggClient, MissingThing, SearchItem}, quota::{self, Account, DailyQuota}, AppState, BggLimit, Error
+};
+
+use super::{paging, OptionalParam};
+
+/// The same search as /search, but delivered as Server-Sent Events:
+/// an "items" event with the page of results, a "things" event with the cached Things,
+/// a "things" event for each batch fetched from BGG as it arrives,
+/// and finally a "complete" event listing the ids that couldn't be fetched, and why.
+#[derive(Route, Clone, Default, Serialize, Deserialize)]
+#[template("/search/events{?query,page,per_page}")]
+pub(crate) struct Nick {
+    query: String,
+    page: OptionalParam<usize>,
+    per_page: OptionalParam<usize>,
+}
+
+pub(crate) fn route() -> String {
+    Nick::axum_route()
+}
+
+#[derive(Serialize)]
+struct ItemsEvent {
+    #[serde(flatten)]
+    resource_fields: ResourceFields<Nick>,
+    total: usize,
+    items: Vec<SearchItem>,
+}
+
+#[derive(Serialize)]
+struct CompleteEvent {
+    missing: Vec<MissingThing>,
+}
+
+fn event(name: &str, data: impl Serialize) -> Result<Event, Error> {
+    Ok(Event::default().event(name).data(serde_json::to_string(&data)?))
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn get(
+    State(db): State<Pool<Postgres>>,
+    State(client): State<BggClient>,
+    State(daily_quota): State<DailyQuota>,
+    who: Identity,
+    tally: Tally,
+    State(bgg_limit): State<BggLimit>,
+    req: NestedRoute<Nick>
+) -> Result<impl IntoResponse, Error> {
+    quota::check(&db, daily_quota, &who).await?;
+    let client = client.on_behalf_of(Account::new(db.clone(), who, tally));
+    let (page, per_page) = paging(req.nick.page, req.nick.per_page);
+    let mut pending = start_search(client, &db, req.nick.query.clone(), page, per_page, bgg_limit.into()).await?;
+
+    let items = event("items", ItemsEvent{
+        resource_fields: req.resource_fields(
+            "api:searchThingEvents",
+            vec![ op(ActionType::View)]
+        )?,
+        total: pending.total,
+        items: std::mem::take(&mut pending.items),
+    })?;
+    let cached = event("things", std::mem::take(&mut pending.cached))?;
+
+    let (tx, rx) = mpsc::channel::<Result<Event, Infallible>>(4);
+    tokio::spawn(async move {
+        if tx.send(Ok(items)).await.is_err() || tx.send(Ok(cached)).await.is_err() {
+            return;
+        }
+
+        while let Some(things) = pending.next_batch().await {
+            if things.is_empty() {
+                continue
+            }
+            match event("things", things) {
+                Ok(ev) => if tx.send(Ok(ev)).await.is_err() {
+                    debug!("search event stream closed early");
+                    return;
+                },
+                Err(err) => debug!("error serializing Things: {err:?}"),
+            }
+        }
+
+        match event("complete", CompleteEvent{missing: pending.missing}) {
+            Ok(ev) => { let _ = tx.send(Ok(ev)).await; },
+            Err(err) => debug!("error serializing search completion: {err:?}"),
+        }
+    });
+
+    Ok(Sse::new(ReceiverStream::new(rx)).keep_alive(KeepAlive::default()))
+}
@@ -1,16 +1,39 @@
 use axum::{debug_handler, extract::State, response::IntoResponse, Json};
-use mattak::{hypermedia::{op, ActionType, ResourceFields}, routing::{extract::{ExtractedRoute, NestedRoute}, Route}};
-use mattak_derives::Route;
-use reqwest::{Client, StatusCode};
+use mattak::{
+    hypermedia::{op, ActionType, ResourceFields},
+    routing::{extract::{ExtractedRoute, NestedRoute}, Listable, Route, RouteTemplateString}
+};
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres};
 
-use crate::{bgg_api::fetch_things, db::ThingData, AppState, Error};
+use crate::{audit::Tally, auth::Identity, bgg_api::{fetch_things, BggClient, BggErrorKind}, db::{BggThing, ThingData}, quota::{self, Account, DailyQuota}, AppState, Error};
 
-#[derive(Route, Clone, Default, Serialize, Deserialize)]
-#[template("/thing{?id}")]
+use super::{links::{self, LinkAffordances}, OptionalParam};
+
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub(crate) struct Nick {
     id: String,
+    /// Only answer if the Thing is of this type (BGG's `type`, our `kind`), e.g. boardgame, rpgitem, videogame or boardgameaccessory
+    #[serde(rename = "type")]
+    r#type: OptionalParam<String>,
+}
+
+// Written out, rather than derived: the derive takes variable names from field names, and `type` is a keyword
+impl Route for Nick {
+    fn route_template() -> RouteTemplateString {
+        RouteTemplateString("/thing{?id,type}".to_string(), vec![])
+    }
+}
+
+impl Listable for Nick {
+    fn list_vars(&self) -> Vec<String> {
+        vec!["id".to_string(), "type".to_string()]
+    }
+}
+
+fn wrong_type(id: &str, kind: &str, wanted: &str) -> Error {
+    Error::StatusCode(StatusCode::NOT_FOUND, format!("Thing {id} is a {kind}, not a {wanted}"))
 }
 
 pub(crate) fn route() -> String {
@@ -21,24 +44,48 @@ pub(crate) fn route() -> String {
 struct Response {
     #[serde(flatten)]
     resource_fields: ResourceFields<Nick>,
-    thing: ThingData
+    thing: ThingData,
+    links: LinkAffordances,
 }
 
 #[debug_handler(state = AppState)]
 pub(crate) async fn get(
     State(db): State<Pool<Postgres>>,
-    State(client): State<Client>,
+    State(client): State<BggClient>,
+    State(daily_quota): State<DailyQuota>,
+    who: Identity,
+    tally: Tally,
     req: NestedRoute<Nick>
 ) -> Result<impl IntoResponse, Error> {
-    let things = fetch_things(client, db, vec![req.nick.id.clone()]).await?;
+    // A cached Thing of the wrong type isn't worth asking BGG about again
+    if let Some(wanted) = req.nick.r#type.as_ref() {
+        let cached = BggThing::get_for_bgg_ids(&db, vec![req.nick.id.clone()]).await.map_err(mattak::Error::from)?;
+        if let Some(thing) = cached.iter().find(|thing| thing.data.kind != *wanted) {
+            return Err(wrong_type(&req.nick.id, &thing.data.kind, wanted));
+        }
+    }
+
+    quota::check(&db, daily_quota, &who).await?;
+    let client = client.on_behalf_of(Account::new(db.clone(), who, tally));
+    let things = fetch_things(client, db, vec![req.nick.id.clone()]).await
+        .map_err(|err| match err {
+            // An id BGG won't take is as good as one it has no Thing for
+            Error::Bgg(BggErrorKind::InvalidRequest, message) => Error::StatusCode(StatusCode::NOT_FOUND, format!("No Thing with id {}: {message}", req.nick.id)),
+            err => err,
+        })?;
+
+    if let Some(thing) = things.first() {
+        if let Some(wanted) = req.nick.r#type.as_ref().filter(|wanted| **wanted != thing.data.kind) {
+            return Err(wrong_type(&req.nick.id, &thing.data.kind, wanted));
+        }
 
-    if let Some(thing) = things.get(0) {
         Ok((StatusCode::OK, Json(Response{
             resource_fields: req.resource_fields("api:thingDetail", vec![op(ActionType::View)])?,
-            thing: thing.data.clone()
+            thing: thing.data.clone(),
+            links: links::affordances(&req, &thing.links)?,
         })))
     } else {
-        Err(Error::StatusCode(StatusCode::NOT_FOUND, "No thing by that ID".to_string()))
+        Err(Error::StatusCode(StatusCode::NOT_FOUND, format!("BGG has no Thing with id {}", req.nick.id)))
     }
 
 }
@@ -0,0 +1,225 @@
+//! Refusing biscuits that have been revoked before they expire.
+//!
+//! A biscuit is refused if any of its revocation ids is in our own revoked_biscuit table,
+//! or in the revocation list published by the authority the request's host maps to.
+//! Those lists are JSON arrays of revocation ids, refetched periodically;
+//! if fetching one fails, the last copy of it is kept.
+use std::{collections::{HashMap, HashSet}, sync::{Arc, RwLock}, time::Duration};
+
+use axum::{extract::{Request, State}, middleware::Next, response::Response};
+use mattak::biscuits::AuthContext;
+use reqwest::Client;
+use sqlx::{Pool, Postgres};
+use tokio::{task::JoinSet, time::sleep};
+use tracing::{debug, info, warn};
+
+use crate::{auth, db, Error};
+
+#[derive(Clone, Debug)]
+pub(crate) struct RevocationLists {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    db: Pool<Postgres>,
+    client: Client,
+    /// Authority host => URL of its revocation list
+    sources: HashMap<String, String>,
+    /// Authority host => the ids on its list, as last fetched
+    lists: RwLock<HashMap<String, HashSet<String>>>,
+}
+
+impl RevocationLists {
+    pub fn new(db: Pool<Postgres>, client: Client, sources: Vec<(&str, &str)>) -> Self {
+        let sources = sources.into_iter().map(|(host, url)| (host.to_string(), url.to_string())).collect();
+        Self{inner: Arc::new(Inner{db, client, sources, lists: RwLock::new(HashMap::new())})}
+    }
+
+    /// Fetches every authority's list, keeping the last copy of any that can't be fetched.
+    pub async fn refresh(&self) {
+        let mut fetches = JoinSet::new();
+        for (host, url) in &self.inner.sources {
+            let (client, host, url) = (self.inner.client.clone(), host.clone(), url.clone());
+            fetches.spawn(async move {
+                let result = fetch_list(&client, &url).await;
+                (host, url, result)
+            });
+        }
+
+        while let Some(fetched) = fetches.join_next().await {
+            match fetched {
+                Ok((host, _, Ok(ids))) => {
+                    debug!("{} revoked biscuits for {host}", ids.len());
+                    self.inner.lists.write().expect("revocation lists lock poisoned").insert(host, ids);
+                }
+                Ok((host, url, Err(err))) => warn!("couldn't fetch revocation list for {host} from {url}, keeping the last one: {err:?}"),
+                Err(err) => warn!("revocation list fetch failed: {err:?}"),
+            }
+        }
+    }
+
+    /// Refetches the lists every `every`
+    pub fn spawn_refresher(&self, every: Duration) {
+        if self.inner.sources.is_empty() {
+            return;
+        }
+        let lists = self.clone();
+        tokio::spawn(async move {
+            loop {
+                lists.refresh().await;
+                sleep(every).await;
+            }
+        });
+    }
+
+    /// Those of a biscuit's revocation ids that have been revoked, for a request to `authority`
+    pub async fn revoked(&self, authority: Option<&str>, revocation_ids: &[String]) -> Result<Vec<String>, Error> {
+        let upstream = self.listed(authority, revocation_ids);
+        let mut revoked = db::revoked(&self.inner.db, revocation_ids).await.map_err(mattak::Error::from)?;
+        revoked.extend(upstream);
+        Ok(revoked)
+    }
+
+    /// Those of a biscuit's revocation ids on the list of the authority a request is to
+    fn listed(&self, authority: Option<&str>, revocation_ids: &[String]) -> Vec<String> {
+        let lists = self.inner.lists.read().expect("revocation lists lock poisoned");
+        match authority.and_then(|host| lists.get(host)) {
+            Some(list) => revocation_ids.iter().filter(|id| list.contains(*id)).cloned().collect(),
+            None => vec![],
+        }
+    }
+}
+
+async fn fetch_list(client: &Client, url: &str) -> Result<HashSet<String>, Error> {
+    let rz = client.get(url).send().await?.error_for_status()?;
+    Ok(serde_json::from_slice::<Vec<String>>(&rz.bytes().await?)?.into_iter().collect())
+}
+
+/// Middleware, to go between the biscuits setup and its checks:
+/// marks a revoked biscuit as such, so that the checks refuse it.
+/// If we can't tell whether it's revoked, the request is refused outright.
+pub(crate) async fn check_revoked(State(lists): State<RevocationLists>, mut req: Request, next: Next) -> Result<Response, Error> {
+    let Some(ctx) = req.extensions().get::<AuthContext>() else {
+        return Ok(next.run(req).await);
+    };
+    let Some(ids) = ctx.revocation_ids() else {
+        return Ok(next.run(req).await);
+    };
+
+    let authority = auth::request_authority(req.headers(), req.uri());
+    let revoked = lists.revoked(authority.as_deref(), &ids).await?;
+    if !revoked.is_empty() {
+        info!("refusing revoked biscuit: {revoked:?}");
+        let ctx = ctx.with_revoked_ids(revoked);
+        req.extensions_mut().insert(ctx);
+    }
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use axum::{http::StatusCode, routing::get, Json, Router};
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    /// Serves `list` as a revocation list until `failing` is set, and a 500 after
+    async fn list_server(list: Vec<String>, failing: Arc<AtomicBool>) -> String {
+        let app = Router::new().route("/revoked.json", get(move || async move {
+            if failing.load(Ordering::SeqCst) {
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            } else {
+                Ok(Json(list))
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind list server");
+        let url = format!("http://{}/revoked.json", listener.local_addr().expect("local addr"));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    fn lists(sources: Vec<(&str, &str)>) -> RevocationLists {
+        let db = PgPoolOptions::new().connect_lazy("postgres://localhost/unused").expect("lazy pool");
+        RevocationLists::new(db, Client::new(), sources)
+    }
+
+    #[tokio::test]
+    async fn listed_by_the_requests_authority() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let one = list_server(ids(&["bGVha2Vk"]), failing.clone()).await;
+        let two = list_server(ids(&["c3RvbGVu"]), failing).await;
+        let lists = lists(vec![("one.example.com", &one), ("two.example.com", &two)]);
+        lists.refresh().await;
+
+        let biscuit = ids(&["b2s=", "bGVha2Vk"]);
+        assert_eq!(lists.listed(Some("one.example.com"), &biscuit), ids(&["bGVha2Vk"]));
+        assert!(lists.listed(Some("two.example.com"), &biscuit).is_empty());
+        assert!(lists.listed(Some("elsewhere.example.com"), &biscuit).is_empty());
+        assert!(lists.listed(None, &biscuit).is_empty());
+        assert_eq!(lists.listed(Some("two.example.com"), &ids(&["c3RvbGVu"])), ids(&["c3RvbGVu"]));
+    }
+
+    #[tokio::test]
+    async fn failed_refreshes_keep_the_last_list() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let url = list_server(ids(&["bGVha2Vk"]), failing.clone()).await;
+        let lists = lists(vec![("one.example.com", &url)]);
+        lists.refresh().await;
+
+        failing.store(true, Ordering::SeqCst);
+        lists.refresh().await;
+        assert_eq!(lists.listed(Some("one.example.com"), &ids(&["bGVha2Vk"])), ids(&["bGVha2Vk"]));
+    }
+
+    /// Tests against a database
+    mod db {
+        use axum::{body::Body, extract::Request, http::StatusCode, middleware, routing::get, Router};
+        use biscuit_auth::{macros::{authorizer, biscuit}, KeyPair};
+        use mattak::biscuits::middleware::{check, setup};
+        use sqlx::query;
+        use tower::{ServiceBuilder, ServiceExt};
+
+        use super::super::*;
+        use crate::{auth::AUTH_HEADER, db::testing::TestSchema, keysets::tests::{headers, StubAuthority}};
+
+        #[tokio::test]
+        async fn refusing_revoked_biscuits() {
+            let schema = TestSchema::new().await;
+            let root = KeyPair::new();
+            let authority = StubAuthority::start(vec![root.public()]).await;
+            let revocations = RevocationLists::new(schema.pool.clone(), reqwest::Client::new(), vec![]);
+            let app = Router::new().nest("/api", Router::new()
+                .route("/thing", get(|| async { "ok" }))
+                .layer(ServiceBuilder::new()
+                    .layer(setup(authority.key_sets().await, AUTH_HEADER))
+                    .layer(middleware::from_fn_with_state(revocations, check_revoked))
+                    .layer(check(authorizer!(r#"allow if user($user);"#)))
+                ));
+            let status = |token| {
+                let mut req = Request::get("/api/thing").body(Body::empty()).expect("request");
+                *req.headers_mut() = headers(&token);
+                let app = app.clone();
+                async move { app.oneshot(req).await.expect("response").status() }
+            };
+
+            let kept = biscuit!(r#"user("alice");"#).build(&root).expect("build biscuit");
+            let leaked = biscuit!(r#"user("alice");"#).build(&root).expect("build biscuit");
+            // Revocation ids are the base64 of the biscuit's, as mattak encodes them
+            query(r#"insert into revoked_biscuit (revocation_id, reason) values (replace(encode($1, 'base64'), E'\n', ''), 'leaked')"#)
+                .bind(leaked.revocation_identifiers().remove(0))
+                .execute(&schema.pool).await.expect("revoke biscuit");
+
+            assert_eq!(status(kept).await, StatusCode::OK);
+            assert_eq!(status(leaked).await, StatusCode::UNAUTHORIZED);
+
+            schema.drop().await;
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Prometheus metrics, for seeing what the gateway is doing in production.
+//!
+//! Everything else records counters and histograms with the `metrics` macros, where it happens;
+//! this installs the recorder they go to, and serves what it has gathered at `/metrics`.
+//! That's on an address of its own, so that it isn't exposed to the world along with the API.
+use std::{net::SocketAddr, time::{Duration, Instant}};
+
+use axum::{extract::{MatchedPath, Request}, middleware::Next, response::Response, routing::get, Router};
+use metrics::{describe_counter, describe_histogram, Unit};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+/// How often histograms are rolled over, as the recorder needs us to do for it
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+const DURATION_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+const BATCH_SIZE_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 15.0, 20.0];
+
+fn builder() -> Result<PrometheusBuilder, BuildError> {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_duration_seconds".to_string()), DURATION_BUCKETS)?
+        .set_buckets_for_metric(Matcher::Full("bgg_thing_batch_size".to_string()), BATCH_SIZE_BUCKETS)
+}
+
+/// Installs the global recorder, and keeps it tidy
+pub(crate) fn install() -> Result<PrometheusHandle, BuildError> {
+    let handle = builder()?.install_recorder()?;
+    describe();
+
+    let upkeep = handle.clone();
+    tokio::spawn(async move {
+        loop {
+            sleep(UPKEEP_INTERVAL).await;
+            upkeep.run_upkeep();
+        }
+    });
+    Ok(handle)
+}
+
+fn describe() {
+    describe_counter!("http_requests_total", "Requests to the API, by route, method and status");
+    describe_histogram!("http_request_duration_seconds", Unit::Seconds, "Time until the response to an API request was ready, by route and method");
+    describe_counter!("search_cache_hits_total", "Things on a page of search results that were already cached");
+    describe_counter!("search_cache_misses_total", "Things on a page of search results that had to be fetched from BGG");
+    describe_counter!("bgg_requests_total", "Requests to BGG, by endpoint and status");
+    describe_histogram!("bgg_request_duration_seconds", Unit::Seconds, "Time until BGG responded, by endpoint");
+    describe_histogram!("bgg_thing_batch_size", Unit::Count, "Things asked for per request to BGG");
+    describe_counter!("bgg_thing_fetch_retries_total", "Requests for Things retried after BGG refused them, by status");
+    describe_counter!("bgg_thing_fetch_giving_up_total", "Fetches of Things given up on once BGG had refused them for too long, by the last status");
+    describe_counter!("bgg_unparseable_values_total", "Values in Things from BGG that couldn't be parsed");
+    describe_counter!("bgg_things_stored_total", "Things from BGG stored in the cache");
+    describe_counter!("bgg_thing_store_failures_total", "Things from BGG that couldn't be stored in the cache");
+    describe_histogram!("db_query_duration_seconds", Unit::Seconds, "Database query latency, by query");
+    describe_counter!("rate_limit_rejections_total", "Requests refused for going over their rate limit, by tier");
+}
+
+/// Serves the metrics at `/metrics` on `addr`, in the background
+pub(crate) async fn serve(handle: PrometheusHandle, addr: &str) -> std::io::Result<()> {
+    let app = Router::new().route("/metrics", get(move || async move { handle.render() }));
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("serving metrics on {}", listener.local_addr()?);
+    tokio::spawn(async move {
+        if let Err(err) = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await {
+            warn!("metrics server stopped: {err:?}");
+        }
+    });
+    Ok(())
+}
+
+/// Middleware: counts and times requests by their route's template, rather than the path itself,
+/// so that every Thing id doesn't get a time series of its own.
+pub(crate) async fn track_requests(req: Request, next: Next) -> Response {
+    let route = req.extensions().get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = req.method().to_string();
+    let started = Instant::now();
+
+    let response = next.run(req).await;
+
+    let status = response.status().as_u16().to_string();
+    metrics::histogram!("http_request_duration_seconds", "route" => route.clone(), "method" => method.clone())
+        .record(started.elapsed());
+    metrics::counter!("http_requests_total", "route" => route, "method" => method, "status" => status).increment(1);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_are_bucketed() {
+        let recorder = builder().expect("builder").build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            metrics::histogram!("db_query_duration_seconds", "query" => "known_missing").record(0.003);
+            metrics::histogram!("bgg_thing_batch_size").record(20.0);
+        });
+
+        let rendered = handle.render();
+        assert!(rendered.contains(r#"db_query_duration_seconds_bucket{query="known_missing",le="0.005"} 1"#), "{rendered}");
+        assert!(rendered.contains(r#"db_query_duration_seconds_bucket{query="known_missing",le="0.001"} 0"#), "{rendered}");
+        assert!(rendered.contains(r#"bgg_thing_batch_size_bucket{le="20"} 1"#), "{rendered}");
+    }
+}